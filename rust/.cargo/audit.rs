@@ -0,0 +1,300 @@
+//! On-chain self-check of the splitter's bookkeeping.
+//!
+//! {audit} recomputes the entitlements of all payees and reports every broken invariant
+//! instead of panicking, so it can be used to monitor a deployment.
+
+use crate::splits::Asset;
+use crate::*;
+
+/// Totals of one asset checked by {audit}. `received` covers pooled income as well as credits
+/// and gifts to single payees, and should equal the sum of the other fields.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetAudit {
+    pub token: Option<AccountId>,
+    pub received: Balance,
+    pub released: Balance,
+    pub releasable: Balance,
+    /// Rounding remainder currently left in the pool.
+    pub dust: Balance,
+    /// Dust held for or swept to the treasury.
+    pub dust_collected: Balance,
+    /// Income no capped payee has room for yet.
+    pub undistributed: Balance,
+}
+
+/// A broken invariant found by {audit}.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Violation {
+    pub check: String,
+    pub token: Option<AccountId>,
+    pub account: Option<AccountId>,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuditReport {
+    pub ok: bool,
+    pub assets: Vec<AssetAudit>,
+    pub violations: Vec<Violation>,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Checks, for Ether and every token the pool has seen, that released plus releasable
+     * plus dust adds up to what was received, and that the payee registry is consistent:
+     * `_total_shares` is the sum of `_shares`, and `_payees`, `_payees2` and
+     * `_payee_count_to_payee_address` agree with each other and with `_payee_count`.
+     */
+    pub fn audit(&self) -> AuditReport {
+        let mut violations: Vec<Violation> = vec![];
+        self._audit_registry(&mut violations);
+        let assets: Vec<AssetAudit> = self
+            ._pool_assets()
+            .into_iter()
+            .map(|asset| self._audit_asset(asset, &mut violations))
+            .collect();
+        AuditReport {
+            ok: violations.is_empty(),
+            assets,
+            violations,
+        }
+    }
+}
+
+impl PaymentSplitter {
+    fn _audit_registry(&self, violations: &mut Vec<Violation>) {
+        let count: u64 = self._payee_count as u64;
+        let lengths = [
+            ("payees_length", self._payees.len()),
+            ("payees2_length", self._payees2.len()),
+            ("payee_ids_length", self._payee_count_to_payee_address.len()),
+        ];
+        for (check, length) in lengths {
+            if length != count {
+                violations.push(Self::_violation(check, None, None, count, length));
+            }
+        }
+
+        let mut total_shares: Balance = 0;
+        for index in 0..self._payees.len() {
+            let id: u128 = index as u128 + 1;
+            let account: AccountId = self._payees.get(index).unwrap();
+            if let Some(listed) = self._payees2.get(index) {
+                if listed != id {
+                    violations.push(Self::_violation(
+                        "payees2_id",
+                        None,
+                        Some(account.clone()),
+                        id,
+                        listed,
+                    ));
+                }
+            }
+            match self._payee_count_to_payee_address.get(&id) {
+                Some(mapped) if mapped == account => {}
+                mapped => violations.push(Violation {
+                    check: "payee_id_address".to_string(),
+                    token: None,
+                    account: Some(account.clone()),
+                    expected: account.to_string(),
+                    actual: format!("{:?}", mapped.map(|mapped| mapped.to_string())),
+                }),
+            }
+            total_shares += self._shares.get(&account).unwrap_or(0);
+        }
+        if total_shares != self._total_shares {
+            violations.push(Self::_violation(
+                "total_shares",
+                None,
+                None,
+                total_shares,
+                self._total_shares,
+            ));
+        }
+    }
+
+    fn _audit_asset(&self, asset: Asset, violations: &mut Vec<Violation>) -> AssetAudit {
+        let pooled: Balance = self._pool_received(&asset);
+        let (total_credited, total_released) = match &asset {
+            None => (self._total_credited, self.total_released()),
+            Some(token) => (
+                self.total_credited_erc(token),
+                self.total_released_erc(token),
+            ),
+        };
+
+        let (distribution, dust, unsettled) = match self._cap_allocated.get(&asset) {
+            Some(allocated) => {
+                let unsettled: Balance = pooled.saturating_sub(allocated);
+                let (amounts, dust) = self._cap_distribution(&asset, unsettled);
+                (Some(amounts), dust, unsettled)
+            }
+            None => (None, self.dust(asset.clone()), 0),
+        };
+
+        let mut released: Balance = 0;
+        let mut releasable: Balance = 0;
+        let mut distributed: Balance = 0;
+        for id in 1..=self._payee_count {
+            let account: AccountId = self._payee_by_id(&id);
+            let pooled_share: Balance = match &distribution {
+                Some(amounts) => {
+                    let amount: Balance = Self::_amount_for(amounts, &account);
+                    distributed += amount;
+                    self._cap_carried
+                        .get(&(asset.clone(), account.clone()))
+                        .unwrap_or(0)
+                        + amount
+                }
+                None => self._pooled_entitlement(&asset, &account, pooled),
+            };
+            let (direct, account_released) = match &asset {
+                None => (self.credited(&account), self.released(&account)),
+                Some(token) => (
+                    self.credited_erc(token, &account),
+                    self.released_erc(token, &account),
+                ),
+            };
+            let entitlement: Balance = pooled_share + direct;
+            if account_released > entitlement {
+                violations.push(Self::_violation(
+                    "released_over_entitlement",
+                    asset.clone(),
+                    Some(account),
+                    entitlement,
+                    account_released,
+                ));
+            } else {
+                releasable += entitlement - account_released;
+            }
+            released += account_released;
+        }
+        if released != total_released {
+            violations.push(Self::_violation(
+                "total_released",
+                asset.clone(),
+                None,
+                released,
+                total_released,
+            ));
+        }
+
+        let ledger: DustLedger = self.dust_ledger(asset.clone());
+        let received: Balance = pooled + total_credited;
+        let undistributed: Balance = unsettled.saturating_sub(distributed + dust);
+        let dust_collected: Balance = ledger.held + ledger.swept;
+        let accounted: Balance = released + releasable + dust + dust_collected + undistributed;
+        if accounted != received {
+            violations.push(Self::_violation(
+                "received",
+                asset.clone(),
+                None,
+                received,
+                accounted,
+            ));
+        }
+        AssetAudit {
+            token: asset,
+            received,
+            released,
+            releasable,
+            dust,
+            dust_collected,
+            undistributed,
+        }
+    }
+
+    fn _violation(
+        check: &str,
+        token: Option<AccountId>,
+        account: Option<AccountId>,
+        expected: impl ToString,
+        actual: impl ToString,
+    ) -> Violation {
+        Violation {
+            check: check.to_string(),
+            token,
+            account,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// A splitter that received a pooled donation and a gift to {charlie}, and paid {bob}.
+    fn busy() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate_to_payee(2, None);
+        set_context(&context(bob()));
+        contract.release(bob());
+        contract
+    }
+
+    fn checks(report: &AuditReport) -> Vec<&str> {
+        report
+            .violations
+            .iter()
+            .map(|violation| violation.check.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn consistent_ledger_passes() {
+        let contract: PaymentSplitter = busy();
+        let report: AuditReport = contract.audit();
+        assert!(report.ok, "{:?}", checks(&report));
+        let ether: &AssetAudit = &report.assets[0];
+        assert_eq!(ether.token, None);
+        assert_eq!(ether.released, contract.released(&bob()));
+        assert_eq!(
+            ether.released + ether.releasable + ether.dust,
+            ether.received
+        );
+    }
+
+    #[test]
+    fn corrupted_ledger_is_flagged() {
+        let mut contract: PaymentSplitter = busy();
+        // a release to charlie booked for him but missing from the total
+        contract._released.insert(&charlie(), &1);
+        let report: AuditReport = contract.audit();
+        assert!(!report.ok);
+        assert_eq!(checks(&report), vec!["total_released"]);
+        let violation: &Violation = &report.violations[0];
+        assert_eq!(
+            violation.expected,
+            (contract.total_released() + 1).to_string()
+        );
+        assert_eq!(violation.actual, contract.total_released().to_string());
+    }
+
+    #[test]
+    fn income_missing_from_the_ledger_is_flagged() {
+        let mut contract: PaymentSplitter = busy();
+        // dust booked as collected that the pool never received
+        let mut ledger: DustLedger = contract._dust.get(&None).unwrap_or_default();
+        ledger.held += 1;
+        contract._dust.insert(&None, &ledger);
+        let report: AuditReport = contract.audit();
+        assert_eq!(checks(&report), vec!["received"]);
+        let ether: &AssetAudit = &report.assets[0];
+        assert_eq!(report.violations[0].expected, ether.received.to_string());
+        assert_eq!(
+            report.violations[0].actual,
+            (ether.received + 1).to_string()
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Percentage splits in basis points.
+//!
+//! In basis point mode the shares of all payees are basis points that add up to exactly
+//! {TOTAL_BASIS_POINTS}. Adding a single payee would break the sum, so the split can only be
+//! changed as a whole through {set_full_split}. A split with more payees than fit in one call is
+//! staged in chunks with {stage_full_split} and then applied in chunks with {apply_staged_split}.
+//! Releases and payee changes are locked until its last chunk has run.
+
+use crate::splits::Asset;
+use crate::*;
+use std::collections::HashSet;
+
+/// Shares of all payees add up to this in basis point mode, i.e. 100%.
+pub const TOTAL_BASIS_POINTS: Balance = 10_000;
+
+/// Progress of {apply_staged_split} across calls.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum StagedApply {
+    /// Checking that every current payee is staged, from payee ID `next_id` on.
+    Checking { next_id: u128 },
+    /// Moving the payees left in the staged split into the roster.
+    Applying,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Switches basis point mode on or off. Switching it on requires the shares to already
+     * add up to {TOTAL_BASIS_POINTS}. Can only be called by the current owner.
+     */
+    pub fn set_basis_points_mode(&mut self, enabled: bool) {
+        self._only_owner();
+        self._only_staged_split_idle();
+        if enabled {
+            require!(
+                self._total_shares == TOTAL_BASIS_POINTS,
+                "PaymentSplitter: shares do not add up to 10000 basis points"
+            );
+        }
+        self._basis_points = enabled;
+    }
+
+    /**
+     * @dev Getter for whether shares are expressed in basis points.
+     */
+    pub fn is_basis_points(&self) -> bool {
+        self._basis_points
+    }
+
+    /**
+     * @dev Replaces the shares of all payees in one go. Every current payee must be listed,
+     * listed accounts that are not payees yet are added. In basis point mode the new shares
+     * must add up to exactly {TOTAL_BASIS_POINTS}. The entitlement accrued so far is carried
+     * over, so only income received afterwards follows the new split.
+     * Takes up to {MAX_PAYEES_PER_CALL} payees, see {stage_full_split} for larger splits.
+     * Can only be called by the current owner.
+     */
+    pub fn set_full_split(&mut self, split: Vec<PayeeSpec>) {
+        self._only_owner();
+        require!(
+            split.len() <= MAX_PAYEES_PER_CALL,
+            "PaymentSplitter: too many payees, stage the split in chunks"
+        );
+        for (index, payee) in split.iter().enumerate() {
+            Self::_require_valid_split_payee(payee);
+            require!(
+                split[..index]
+                    .iter()
+                    .all(|other| other.account != payee.account),
+                "PaymentSplitter: duplicate payee in batch"
+            );
+        }
+        self._apply_full_split(split);
+    }
+
+    /**
+     * @dev Adds a chunk of up to {MAX_PAYEES_PER_CALL} payees to the staged split, which
+     * {apply_staged_split} applies like {set_full_split} once it is complete. Payees already
+     * staged cannot be staged again. Can only be called by the current owner.
+     */
+    pub fn stage_full_split(&mut self, split: Vec<PayeeSpec>) {
+        self._only_owner();
+        self._only_staged_split_idle();
+        require!(
+            split.len() <= MAX_PAYEES_PER_CALL,
+            "PaymentSplitter: too many payees, stage the split in chunks"
+        );
+        for payee in split {
+            Self::_require_valid_split_payee(&payee);
+            require!(
+                self._staged_split.insert(&payee.account, &payee).is_none(),
+                "PaymentSplitter: duplicate payee in batch"
+            );
+            self._staged_shares += payee.shares;
+        }
+    }
+
+    /**
+     * @dev Applies the split staged through {stage_full_split} like {set_full_split}, taking up
+     * to `limit` steps per call (all remaining ones if `None`). It first checks that every
+     * current payee is staged, one payee per step, then carries the accrued entitlement over
+     * and moves one staged payee per step into the roster. Returns whether the split is fully
+     * applied. Releases and payee changes are locked from the first call until then.
+     * Can only be called by the current owner.
+     */
+    pub fn apply_staged_split(&mut self, limit: Option<u128>) -> bool {
+        self._only_owner();
+        require!(limit != Some(0), "PaymentSplitter: limit is 0");
+        if self._staged_apply.is_none() {
+            require!(
+                !self._staged_split.is_empty(),
+                "PaymentSplitter: no split staged"
+            );
+            self._only_releases_enabled();
+            if self._basis_points {
+                require!(
+                    self._staged_shares == TOTAL_BASIS_POINTS,
+                    "PaymentSplitter: shares do not add up to 10000 basis points"
+                );
+            }
+            self._staged_apply = Some(StagedApply::Checking { next_id: 1 });
+        }
+
+        let mut budget: u128 = limit.unwrap_or(u128::MAX);
+        while budget > 0 {
+            match self._staged_apply {
+                Some(StagedApply::Checking { next_id }) if next_id <= self._payee_count => {
+                    require!(
+                        self._staged_split
+                            .get(&self._payee_by_id(&next_id))
+                            .is_some(),
+                        "PaymentSplitter: split leaves out a current payee"
+                    );
+                    self._staged_apply = Some(StagedApply::Checking {
+                        next_id: next_id + 1,
+                    });
+                }
+                Some(StagedApply::Checking { .. }) => {
+                    for asset in self._pool_assets() {
+                        self._carry_over(&asset);
+                    }
+                    self._staged_apply = Some(StagedApply::Applying);
+                }
+                _ => {
+                    let account: AccountId = self
+                        ._staged_split
+                        .keys_as_vector()
+                        .get(self._staged_split.len() - 1)
+                        .unwrap();
+                    let payee: PayeeSpec = self._staged_split.remove(&account).unwrap();
+                    self._set_split_payee(payee);
+                    if self._staged_split.is_empty() {
+                        self._staged_apply = None;
+                        self._staged_shares = 0;
+                        self._emit_split_set();
+                        return true;
+                    }
+                }
+            }
+            budget -= 1;
+        }
+        false
+    }
+
+    /**
+     * @dev Drops the split staged through {stage_full_split}. Once {apply_staged_split} has
+     * started changing shares, the split can no longer be dropped.
+     * Can only be called by the current owner.
+     */
+    pub fn clear_staged_split(&mut self) {
+        self._only_owner();
+        require!(
+            !matches!(self._staged_apply, Some(StagedApply::Applying)),
+            "PaymentSplitter: staged split is being applied"
+        );
+        self._staged_split.clear();
+        self._staged_apply = None;
+        self._staged_shares = 0;
+    }
+
+    /**
+     * @dev Getter for the number of payees staged through {stage_full_split}.
+     */
+    pub fn staged_split_len(&self) -> u64 {
+        self._staged_split.len()
+    }
+
+    /**
+     * @dev Getter for whether {apply_staged_split} has started and not finished yet.
+     */
+    pub fn is_applying_staged_split(&self) -> bool {
+        self._staged_apply.is_some()
+    }
+
+    /**
+     * @dev Getter for the share of `account` as a percentage with two decimals, e.g. "12.50".
+     * Exact in basis point mode, rounded down otherwise.
+     */
+    pub fn share_percentage(&self, account: AccountId) -> String {
+        let shares: Balance = self._shares.get(&account).unwrap_or(0);
+        let basis_points: Balance = match self._total_shares {
+            0 => 0,
+            total_shares => math::mul_div(shares, TOTAL_BASIS_POINTS, total_shares),
+        };
+        format!("{}.{:02}", basis_points / 100, basis_points % 100)
+    }
+
+    /**
+     * @dev Getter for up to `limit` payees with their share as a percentage, starting at payee
+     * ID `from_id` (IDs start at 1).
+     */
+    pub fn percentages(&self, from_id: u128, limit: u128) -> Vec<(AccountId, String)> {
+        let first: u128 = std::cmp::max(from_id, 1);
+        let last: u128 = std::cmp::min(first.saturating_add(limit), self._payee_count + 1);
+        (first..last)
+            .map(|id| {
+                let account: AccountId = self._payee_by_id(&id);
+                (account.clone(), self.share_percentage(account))
+            })
+            .collect()
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Throws if `payee` cannot be part of a split.
+     */
+    fn _require_valid_split_payee(payee: &PayeeSpec) {
+        require!(payee.shares > 0, "PaymentSplitter: shares are 0");
+        require!(
+            !payee.account.as_str().is_empty(),
+            "PaymentSplitter: account is the zero address"
+        );
+    }
+
+    /**
+     * @dev Replaces the shares of all payees with `split`, whose payees are valid and distinct.
+     */
+    fn _apply_full_split(&mut self, split: Vec<PayeeSpec>) {
+        self._only_releases_enabled();
+        let total_shares: Balance = split.iter().map(|payee| payee.shares).sum();
+        let listed: HashSet<&AccountId> = split.iter().map(|payee| &payee.account).collect();
+        require!(
+            (1..=self._payee_count).all(|id| listed.contains(&self._payee_by_id(&id))),
+            "PaymentSplitter: split leaves out a current payee"
+        );
+        if self._basis_points {
+            require!(
+                total_shares == TOTAL_BASIS_POINTS,
+                "PaymentSplitter: shares do not add up to 10000 basis points"
+            );
+        }
+
+        for asset in self._pool_assets() {
+            self._carry_over(&asset);
+        }
+        for payee in split {
+            self._set_split_payee(payee);
+        }
+        self._emit_split_set();
+    }
+
+    /**
+     * @dev Sets the shares and metadata of `payee`, adding it if it is not a payee yet. The
+     * accrued entitlement must have been carried over already.
+     */
+    fn _set_split_payee(&mut self, payee: PayeeSpec) {
+        if payee.splitter {
+            self._payee_is_splitter.insert(&payee.account, &true);
+        } else {
+            self._payee_is_splitter.remove(&payee.account);
+        }
+        match self._shares.get(&payee.account) {
+            Some(shares) => {
+                self._total_shares = self._total_shares - shares + payee.shares;
+                self._shares.insert(&payee.account, &payee.shares);
+                self._payee_name.insert(&payee.account, &payee.name);
+                self._target_chain.insert(&payee.account, &payee.chain);
+                self._target_wallet
+                    .insert(&payee.account, &payee.wallet.to_string());
+            }
+            None => self._register_payee(
+                payee.account,
+                payee.shares,
+                payee.name,
+                payee.chain,
+                payee.wallet,
+            ),
+        }
+    }
+
+    /**
+     * @dev Emits the new total shares once a full split is applied.
+     */
+    fn _emit_split_set(&self) {
+        events::emit(
+            "split_set",
+            json!({
+                "total_shares": self._total_shares.to_string(),
+                "basis_points": self._basis_points,
+            }),
+        );
+    }
+
+    /**
+     * @dev Throws while {apply_staged_split} has started and not finished yet.
+     */
+    pub(crate) fn _only_staged_split_idle(&self) {
+        require!(
+            self._staged_apply.is_none(),
+            "PaymentSplitter: staged split is being applied"
+        );
+    }
+
+    /**
+     * @dev Throws in basis point mode, where payees can only be added through {set_full_split}.
+     */
+    pub(crate) fn _only_weighted_shares(&self) {
+        require!(
+            !self._basis_points,
+            "PaymentSplitter: use set_full_split in basis point mode"
+        );
+    }
+
+    /**
+     * @dev Assets the shared pool has seen: Ether and every token donated or ledgered so far.
+     */
+    pub(crate) fn _pool_assets(&self) -> Vec<Asset> {
+        let mut assets: Vec<Asset> = vec![None];
+        for token in self._erc20_donated.keys() {
+            assets.push(Some(token));
+        }
+        for asset in self._cap_allocated.keys() {
+            if !assets.contains(&asset) {
+                assets.push(asset);
+            }
+        }
+        assets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    fn payee(account: AccountId, shares: Balance) -> PayeeSpec {
+        PayeeSpec {
+            account: account.clone(),
+            shares,
+            name: account.to_string(),
+            chain: "near".to_string(),
+            wallet: account,
+            splitter: false,
+        }
+    }
+
+    /// A splitter with a donation of 4 NEAR and a staged split giving {bob} 2, {charlie} 2 and
+    /// a new payee 4 shares. Returns it with the income of the donation.
+    fn staged() -> (PaymentSplitter, Balance) {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        set_context(&context(owner()));
+        contract.stage_full_split(vec![payee(bob(), 2), payee(charlie(), 2)]);
+        contract.stage_full_split(vec![payee(accounts(3), 4)]);
+        let income: Balance = contract._pool_received(&None);
+        (contract, income)
+    }
+
+    #[test]
+    fn staged_split_is_applied_in_chunks() {
+        let (mut contract, income) = staged();
+        // 2 payees checked, the carry-over, then 3 payees moved
+        for _ in 0..5 {
+            assert!(!contract.apply_staged_split(Some(1)));
+            assert!(contract.is_applying_staged_split());
+        }
+        assert!(contract.apply_staged_split(Some(1)));
+        assert!(!contract.is_applying_staged_split());
+        assert_eq!(contract.staged_split_len(), 0);
+        assert_eq!(contract.total_shares(), 8);
+        assert_eq!(contract.shares(accounts(3)), 4);
+        // income received before keeps following the old split
+        assert_eq!(contract.releasable(&bob()), income / 4);
+        assert_eq!(contract.releasable(&accounts(3)), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: staged split is being applied")]
+    fn releases_are_locked_until_the_last_chunk() {
+        let (mut contract, _) = staged();
+        contract.apply_staged_split(Some(4));
+        contract.release(bob());
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: split leaves out a current payee")]
+    fn staged_split_must_list_every_payee() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.stage_full_split(vec![payee(bob(), 2)]);
+        contract.apply_staged_split(None);
+    }
+
+    #[test]
+    fn staged_split_can_be_dropped_while_checked() {
+        let (mut contract, _) = staged();
+        assert!(!contract.apply_staged_split(Some(1)));
+        contract.clear_staged_split();
+        assert!(!contract.is_applying_staged_split());
+        assert_eq!(contract.staged_split_len(), 0);
+        contract.release(bob());
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: staged split is being applied")]
+    fn staged_split_cannot_be_dropped_once_shares_change() {
+        let (mut contract, _) = staged();
+        contract.apply_staged_split(Some(3));
+        contract.clear_staged_split();
+    }
+}
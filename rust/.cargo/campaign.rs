@@ -0,0 +1,639 @@
+//! Time-boxed fundraising campaigns.
+//!
+//! While a campaign runs, donations are held in escrow and releases are disabled. Once the
+//! Ether raised reaches the goal, releases are enabled and funds flow through the normal split.
+//! If the deadline passes first, donors can claim pro-rata refunds of their Ether and token
+//! contributions. Refunds left unclaimed {REFUND_CLAIM_PERIOD} after the deadline go back to
+//! the pool when the owner ends the campaign. Ending clears the campaign records in batches, so
+//! it works whatever the number of contributors.
+
+use crate::*;
+
+/// Time donors have after the deadline of a failed campaign to claim their refunds (30 days in
+/// nanoseconds)
+pub const REFUND_CLAIM_PERIOD: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+const GAS_FOR_REFUND_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+/// A running fundraising campaign. `goal` and `raised` are in Ether, before platform fees;
+/// token donations are held in escrow too but do not count towards the goal.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Campaign {
+    pub goal: Balance,
+    pub deadline: u64,
+    pub raised: Balance,
+    /// Ether available for refunds, fixed by the first refund after the campaign failed.
+    pub refund_pool: Option<Balance>,
+    /// Ether contributions not refunded yet.
+    pub unclaimed: Balance,
+    /// Set once {end_campaign} started clearing the campaign records.
+    pub ending: bool,
+}
+
+#[derive(Serialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum CampaignStatus {
+    Active,
+    Succeeded,
+    Failed,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Starts a campaign raising `goal` in Ether before `deadline` (block timestamp in
+     * nanoseconds). Releases are disabled until the goal is met, so pending payments should be
+     * released first. Can only be called by the current owner.
+     */
+    pub fn start_campaign(&mut self, goal: Balance, deadline: u64) {
+        self._only_owner();
+        require!(
+            self._campaign.is_none(),
+            "PaymentSplitter: a campaign is already running"
+        );
+        require!(goal > 0, "PaymentSplitter: goal is 0");
+        require!(
+            deadline > env::block_timestamp(),
+            "PaymentSplitter: deadline is in the past"
+        );
+        self._campaign = Some(Campaign {
+            goal,
+            deadline,
+            raised: 0,
+            refund_pool: None,
+            unclaimed: 0,
+            ending: false,
+        });
+        events::emit(
+            "campaign_started",
+            json!({
+                "goal": goal.to_string(),
+                "deadline": deadline.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Closes the current campaign and returns to normal mode. A succeeded campaign can be
+     * closed at any time, a failed one once every contribution has been refunded or the
+     * {REFUND_CLAIM_PERIOD} after its deadline is over. Contributions not refunded by then stay
+     * in the pool and follow the normal split. Can only be called by the current owner.
+     * Clears up to `limit` campaign records (all remaining ones if `None`) per call and returns
+     * whether the campaign is closed. Refunds stop with the first call; releases stay disabled
+     * after a failed campaign until the last call.
+     */
+    pub fn end_campaign(&mut self, limit: Option<u128>) -> bool {
+        self._only_owner();
+        require!(limit != Some(0), "PaymentSplitter: limit is 0");
+        let mut campaign: Campaign = self
+            ._campaign
+            .clone()
+            .unwrap_or_else(|| env::panic_str("PaymentSplitter: no campaign running"));
+        if !campaign.ending {
+            match self.campaign_status() {
+                Some(CampaignStatus::Succeeded) => {}
+                Some(CampaignStatus::Failed) => {
+                    let settled: bool =
+                        campaign.unclaimed == 0 && self._campaign_erc20_contributions.is_empty();
+                    require!(
+                        settled || env::block_timestamp() > campaign.deadline + REFUND_CLAIM_PERIOD,
+                        "PaymentSplitter: refunds are still outstanding"
+                    );
+                    if !settled {
+                        self._expire_refunds(campaign.unclaimed);
+                    }
+                }
+                _ => env::panic_str("PaymentSplitter: campaign is still active"),
+            }
+            campaign.ending = true;
+            self._campaign = Some(campaign);
+        }
+
+        let mut budget: u128 = limit.unwrap_or(u128::MAX);
+        let done: bool = drain(&mut self._campaign_contributions, &mut budget)
+            && drain(&mut self._campaign_fees, &mut budget)
+            && self._drain_erc20_contributions(&mut budget)
+            && drain(&mut self._campaign_erc20_raised, &mut budget)
+            && drain(&mut self._campaign_erc20_unclaimed, &mut budget);
+        if done {
+            self._campaign = None;
+            events::emit("campaign_ended", json!({}));
+        }
+        done
+    }
+
+    /**
+     * @dev Getter for the current campaign, if any.
+     */
+    pub fn campaign(&self) -> Option<Campaign> {
+        self._campaign.clone()
+    }
+
+    /**
+     * @dev Getter for the status of the current campaign, if any.
+     */
+    pub fn campaign_status(&self) -> Option<CampaignStatus> {
+        self._campaign.as_ref().map(|campaign| {
+            if campaign.raised >= campaign.goal {
+                CampaignStatus::Succeeded
+            } else if env::block_timestamp() > campaign.deadline {
+                CampaignStatus::Failed
+            } else {
+                CampaignStatus::Active
+            }
+        })
+    }
+
+    /**
+     * @dev Getter for the Ether `donor` contributed to the current campaign and not yet got refunded.
+     */
+    pub fn campaign_contribution(&self, donor: &AccountId) -> Balance {
+        self._campaign_contributions.get(donor).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the `token` amount contributed to the current campaign and not yet
+     * refunded.
+     */
+    pub fn campaign_unclaimed_erc(&self, token: &AccountId) -> Balance {
+        self._campaign_erc20_unclaimed.get(token).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the `token` amount `donor` contributed to the current campaign and not yet
+     * got refunded.
+     */
+    pub fn campaign_contribution_erc(&self, token: &AccountId, donor: &AccountId) -> Balance {
+        match self._campaign_erc20_contributions.get(token) {
+            Some(value) => value.get(donor).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /**
+     * @dev Refunds the caller's Ether contribution to a failed campaign, platform fee included,
+     * pro-rata to the Ether still held by the contract, which normally covers it in full.
+     */
+    pub fn claim_refund(&mut self) {
+        self._only_failed_campaign();
+        let donor: AccountId = Self::_msg_sender();
+        let contribution: Balance = self.campaign_contribution(&donor);
+        require!(contribution > 0, "PaymentSplitter: nothing to refund");
+
+        let mut campaign: Campaign = self._campaign.clone().unwrap();
+        let refund_pool: Balance = *campaign
+            .refund_pool
+            .get_or_insert(std::cmp::min(campaign.raised, env::account_balance()));
+        let refund: Balance = math::mul_div(contribution, refund_pool, campaign.raised);
+        campaign.unclaimed -= contribution;
+        self._campaign = Some(campaign);
+        self._campaign_contributions.remove(&donor);
+        let fee: Balance = self._refund_contribution(&None, &donor, contribution);
+
+        Promise::new(donor.clone()).transfer(refund).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_REFUND_CALLBACK)
+                .on_refund_complete(donor.clone(), None, contribution, fee),
+        );
+        events::emit(
+            "campaign_refund",
+            json!({
+                "to": donor,
+                "amount": refund.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Refunds the caller's `token` contribution to a failed campaign, platform fee
+     * included.
+     */
+    pub fn claim_refund_erc(&mut self, token: AccountId) {
+        self._only_failed_campaign();
+        let donor: AccountId = Self::_msg_sender();
+        let refund: Balance = self.campaign_contribution_erc(&token, &donor);
+        require!(refund > 0, "PaymentSplitter: nothing to refund");
+
+        let mut contributions: UnorderedMap<AccountId, Balance> =
+            self._campaign_erc20_contributions.get(&token).unwrap();
+        contributions.remove(&donor);
+        let fee: Balance = self._refund_contribution(&Some(token.clone()), &donor, refund);
+        if contributions.is_empty() {
+            self._campaign_erc20_contributions.remove(&token);
+        } else {
+            self._campaign_erc20_contributions
+                .insert(&token, &contributions);
+        }
+        self._campaign_erc20_unclaimed
+            .insert(&token, &(self.campaign_unclaimed_erc(&token) - refund));
+
+        ext_ft::ext(token.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(donor.to_string(), refund.to_string(), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_REFUND_CALLBACK)
+                    .on_refund_complete(donor.clone(), Some(token.clone()), refund, fee),
+            );
+        events::emit(
+            "campaign_refund",
+            json!({
+                "token": token,
+                "to": donor,
+                "amount": refund.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves a refund. If the transfer failed, the `contribution` and its platform `fee`
+     * are restored so the donor can claim it again, or left in the pool if the campaign was
+     * ended in the meantime.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_refund_complete(
+        &mut self,
+        donor: AccountId,
+        token: Option<AccountId>,
+        contribution: Balance,
+        fee: Balance,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!(
+                    "Refund of {} to {} failed, contribution restored",
+                    contribution,
+                    donor
+                );
+                self._book_donated(&token, contribution);
+                let mut ledger: FeeLedger = self._fees.get(&token).unwrap_or_default();
+                ledger.held += fee;
+                self._fees.insert(&token, &ledger);
+                if self._campaign_running() {
+                    self._restore_contribution(&donor, &token, contribution);
+                    if fee > 0 {
+                        self._campaign_fees.insert(&(token, donor), &fee);
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Books a donation of `amount`, of which `fee` was taken as platform fee, as a
+     * contribution to the current campaign. Donations are refused once the campaign failed.
+     */
+    pub(crate) fn _campaign_contribute(
+        &mut self,
+        donor: &AccountId,
+        token: &Option<AccountId>,
+        amount: Balance,
+        fee: Balance,
+    ) {
+        require!(
+            self.campaign_status() != Some(CampaignStatus::Failed),
+            "PaymentSplitter: campaign failed"
+        );
+        if fee > 0 {
+            let key = (token.clone(), donor.clone());
+            let fee_x: Balance = fee + self._campaign_fees.get(&key).unwrap_or(0);
+            self._campaign_fees.insert(&key, &fee_x);
+        }
+        match token {
+            None => {
+                let contribution_x: Balance = amount + self.campaign_contribution(donor);
+                self._campaign_contributions.insert(donor, &contribution_x);
+                let mut campaign: Campaign = self._campaign.clone().unwrap();
+                campaign.raised += amount;
+                campaign.unclaimed += amount;
+                self._campaign = Some(campaign);
+            }
+            Some(token) => {
+                let mut contributions: UnorderedMap<AccountId, Balance> =
+                    match self._campaign_erc20_contributions.get(token) {
+                        Some(value) => value,
+                        None => UnorderedMap::new([b"G".as_slice(), token.as_bytes()].concat()),
+                    };
+                let contribution_x: Balance = amount + contributions.get(donor).unwrap_or(0);
+                contributions.insert(donor, &contribution_x);
+                self._campaign_erc20_contributions
+                    .insert(token, &contributions);
+                let raised_x: Balance =
+                    amount + self._campaign_erc20_raised.get(token).unwrap_or(0);
+                self._campaign_erc20_raised.insert(token, &raised_x);
+                self._campaign_erc20_unclaimed
+                    .insert(token, &(amount + self.campaign_unclaimed_erc(token)));
+            }
+        }
+    }
+
+    /**
+     * @dev Takes a refunded `contribution` of `donor` out of the ledger and reverses the platform
+     * fee taken from it. Returns the fee.
+     */
+    fn _refund_contribution(
+        &mut self,
+        token: &Option<AccountId>,
+        donor: &AccountId,
+        contribution: Balance,
+    ) -> Balance {
+        let fee: Balance = self
+            ._campaign_fees
+            .remove(&(token.clone(), donor.clone()))
+            .unwrap_or(0);
+        self._book_refunded(token, contribution);
+        self._reverse_platform_fee(token, fee);
+        fee
+    }
+
+    /**
+     * @dev Takes `amount` back out of the Ether contribution `donor` just made, used to pay the
+     * storage of a donation out of the donation, so refunds never pay back more than was booked.
+     */
+    pub(crate) fn _campaign_take_back(&mut self, donor: &AccountId, amount: Balance) {
+        let contribution_x: Balance = self.campaign_contribution(donor) - amount;
+        self._campaign_contributions.insert(donor, &contribution_x);
+        let mut campaign: Campaign = self._campaign.clone().unwrap();
+        campaign.raised -= amount;
+        campaign.unclaimed -= amount;
+        self._campaign = Some(campaign);
+    }
+
+    /**
+     * @dev Puts a refunded `contribution` of `donor` back in the contributions of the current
+     * campaign, without counting it towards the amount raised again.
+     */
+    fn _restore_contribution(
+        &mut self,
+        donor: &AccountId,
+        token: &Option<AccountId>,
+        contribution: Balance,
+    ) {
+        match token {
+            None => {
+                let contribution_x: Balance = contribution + self.campaign_contribution(donor);
+                self._campaign_contributions.insert(donor, &contribution_x);
+                let mut campaign: Campaign = self._campaign.clone().unwrap();
+                campaign.unclaimed += contribution;
+                self._campaign = Some(campaign);
+            }
+            Some(token) => {
+                let mut contributions: UnorderedMap<AccountId, Balance> =
+                    match self._campaign_erc20_contributions.get(token) {
+                        Some(value) => value,
+                        None => UnorderedMap::new([b"G".as_slice(), token.as_bytes()].concat()),
+                    };
+                let contribution_x: Balance = contribution + contributions.get(donor).unwrap_or(0);
+                contributions.insert(donor, &contribution_x);
+                self._campaign_erc20_contributions
+                    .insert(token, &contributions);
+                self._campaign_erc20_unclaimed
+                    .insert(token, &(contribution + self.campaign_unclaimed_erc(token)));
+            }
+        }
+    }
+
+    /**
+     * @dev Reports the refunds left unclaimed after the {REFUND_CLAIM_PERIOD}, `unclaimed` Ether
+     * and the running token totals. The contributions are still booked as donated, so once
+     * {end_campaign} has cleared them they stay in the pool.
+     */
+    fn _expire_refunds(&self, unclaimed: Balance) {
+        let unclaimed_erc: HashMap<AccountId, U128> = self
+            ._campaign_erc20_unclaimed
+            .iter()
+            .map(|(token, amount)| (token, U128(amount)))
+            .collect();
+        events::emit(
+            "campaign_refunds_expired",
+            json!({
+                "amount": unclaimed.to_string(),
+                "tokens": unclaimed_erc,
+            }),
+        );
+    }
+
+    /**
+     * @dev Clears up to `budget` token contributions, a token at a time, and takes them off the
+     * budget. Returns whether all of them are cleared.
+     */
+    fn _drain_erc20_contributions(&mut self, budget: &mut u128) -> bool {
+        while *budget > 0 {
+            let len: u64 = self._campaign_erc20_contributions.len();
+            if len == 0 {
+                return true;
+            }
+            let token: AccountId = self
+                ._campaign_erc20_contributions
+                .keys_as_vector()
+                .get(len - 1)
+                .unwrap();
+            let mut contributions: UnorderedMap<AccountId, Balance> =
+                self._campaign_erc20_contributions.get(&token).unwrap();
+            if drain(&mut contributions, budget) {
+                self._campaign_erc20_contributions.remove(&token);
+            } else {
+                self._campaign_erc20_contributions
+                    .insert(&token, &contributions);
+            }
+        }
+        self._campaign_erc20_contributions.is_empty()
+    }
+
+    /**
+     * @dev Returns whether a campaign is running and {end_campaign} has not started closing it.
+     */
+    pub(crate) fn _campaign_running(&self) -> bool {
+        matches!(&self._campaign, Some(campaign) if !campaign.ending)
+    }
+
+    /**
+     * @dev Throws while a campaign is running and has not reached its goal, or while a staged
+     * split is being applied.
+     */
+    pub(crate) fn _only_releases_enabled(&self) {
+        self._only_staged_split_idle();
+        require!(
+            matches!(
+                self.campaign_status(),
+                None | Some(CampaignStatus::Succeeded)
+            ),
+            "PaymentSplitter: campaign goal not reached"
+        );
+    }
+
+    /**
+     * @dev Throws unless the current campaign passed its deadline without reaching the goal.
+     */
+    fn _only_failed_campaign(&self) {
+        require!(
+            self.campaign_status() == Some(CampaignStatus::Failed),
+            "PaymentSplitter: campaign has not failed"
+        );
+        require!(
+            self._campaign_running(),
+            "PaymentSplitter: campaign is being ended"
+        );
+    }
+}
+
+/**
+ * @dev Removes up to `budget` entries from `map`, last first so no entry has to be moved, and
+ * takes them off the budget. Returns whether `map` is empty.
+ */
+fn drain<K, V>(map: &mut UnorderedMap<K, V>, budget: &mut u128) -> bool
+where
+    K: BorshSerialize + BorshDeserialize,
+    V: BorshSerialize + BorshDeserialize,
+{
+    while *budget > 0 && !map.is_empty() {
+        let key: K = map.keys_as_vector().get(map.len() - 1).unwrap();
+        map.remove(&key);
+        *budget -= 1;
+    }
+    map.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+
+    const DEADLINE: u64 = 1_000;
+
+    fn token() -> AccountId {
+        "token.near".parse().unwrap()
+    }
+
+    /// A splitter running a campaign for `goal` with Ether donations of 1 NEAR from {owner},
+    /// {bob} and {charlie}, and a donation of 1000 {token} from {bob}.
+    fn campaign(goal: Balance) -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_accepted_token(token(), Some(1));
+        contract.start_campaign(goal, DEADLINE);
+        for donor in [owner(), bob(), charlie()] {
+            set_context(context(donor).attached_deposit(ONE_NEAR));
+            contract.donate(None, None);
+        }
+        set_context(&context(token()));
+        contract.ft_on_transfer(bob(), U128(1000), String::new());
+        contract
+    }
+
+    fn at(predecessor: AccountId, timestamp: u64) -> VMContextBuilder {
+        let mut builder: VMContextBuilder = context(predecessor);
+        builder.block_timestamp(timestamp);
+        builder
+    }
+
+    #[test]
+    fn succeeded_campaign_ends_in_batches() {
+        let mut contract: PaymentSplitter = campaign(ONE_NEAR);
+        let campaign: Campaign = contract.campaign().unwrap();
+        assert!(campaign.raised >= ONE_NEAR);
+        assert_eq!(campaign.unclaimed, campaign.raised);
+        assert_eq!(contract.campaign_unclaimed_erc(&token()), 1000);
+
+        set_context(&context(owner()));
+        let mut calls: u32 = 1;
+        while !contract.end_campaign(Some(2)) {
+            assert!(contract.campaign().unwrap().ending);
+            calls += 1;
+        }
+        // 3 Ether contributions, 1 token contribution and the raised and unclaimed token totals
+        assert_eq!(calls, 3);
+        assert!(contract.campaign().is_none());
+        assert_eq!(contract.campaign_contribution(&bob()), 0);
+        assert_eq!(contract.campaign_contribution_erc(&token(), &bob()), 0);
+        assert_eq!(contract.campaign_unclaimed_erc(&token()), 0);
+    }
+
+    #[test]
+    fn failed_campaign_refunds_contributions() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        let contribution: Balance = contract.campaign_contribution(&bob());
+        let unclaimed: Balance = contract.campaign().unwrap().unclaimed;
+        set_context(&at(bob(), DEADLINE + 1));
+        assert!(contract.campaign_status() == Some(CampaignStatus::Failed));
+
+        contract.claim_refund();
+        assert_eq!(contract.campaign_contribution(&bob()), 0);
+        assert_eq!(
+            contract.campaign().unwrap().unclaimed,
+            unclaimed - contribution
+        );
+        assert_eq!(call("on_refund_complete").receiver, splitter_account());
+
+        // a failed transfer restores the contribution
+        set_callback_context(&at(splitter_account(), DEADLINE + 1), PromiseResult::Failed);
+        assert!(!contract.on_refund_complete(bob(), None, contribution, 0));
+        assert_eq!(contract.campaign_contribution(&bob()), contribution);
+        assert_eq!(contract.campaign().unwrap().unclaimed, unclaimed);
+    }
+
+    #[test]
+    fn failed_campaign_refunds_tokens() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        set_context(&at(bob(), DEADLINE + 1));
+        contract.claim_refund_erc(token());
+        assert_eq!(contract.campaign_contribution_erc(&token(), &bob()), 0);
+        assert_eq!(contract.campaign_unclaimed_erc(&token()), 0);
+        let transfer: Call = call("ft_transfer");
+        assert_eq!(transfer.receiver, token());
+        assert_eq!(transfer.deposit, 1);
+        assert_eq!(transfer.args["receiver_id"], json!(bob()));
+        assert_eq!(transfer.args["amount"], json!("1000"));
+
+        set_callback_context(&at(splitter_account(), DEADLINE + 1), PromiseResult::Failed);
+        assert!(!contract.on_refund_complete(bob(), Some(token()), 1000, 0));
+        assert_eq!(contract.campaign_contribution_erc(&token(), &bob()), 1000);
+        assert_eq!(contract.campaign_unclaimed_erc(&token()), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: refunds are still outstanding")]
+    fn failed_campaign_keeps_refunds_open_for_the_claim_period() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        set_context(&at(owner(), DEADLINE + REFUND_CLAIM_PERIOD));
+        contract.end_campaign(None);
+    }
+
+    #[test]
+    fn expired_refunds_stay_in_the_pool() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        let donated: Balance = contract.total_donated();
+        set_context(&at(owner(), DEADLINE + REFUND_CLAIM_PERIOD + 1));
+        assert!(!contract.end_campaign(Some(1)));
+        assert!(contract.campaign().unwrap().ending);
+        assert!(contract.end_campaign(None));
+        assert!(contract.campaign().is_none());
+        assert_eq!(contract.total_donated(), donated);
+        assert_eq!(contract.releasable(&accounts(2)), donated * 3 / 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: campaign is being ended")]
+    fn refunds_stop_once_ending_started() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        set_context(&at(owner(), DEADLINE + REFUND_CLAIM_PERIOD + 1));
+        contract.end_campaign(Some(1));
+        set_context(&at(bob(), DEADLINE + REFUND_CLAIM_PERIOD + 1));
+        contract.claim_refund();
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: campaign goal not reached")]
+    fn releases_stay_disabled_until_a_failed_campaign_is_ended() {
+        let mut contract: PaymentSplitter = campaign(100 * ONE_NEAR);
+        set_context(&at(owner(), DEADLINE + REFUND_CLAIM_PERIOD + 1));
+        contract.end_campaign(Some(1));
+        contract.release(bob());
+    }
+}
@@ -0,0 +1,412 @@
+//! Caps on the pooled payout of single payees.
+//!
+//! A payee can be capped at a maximum pooled payout per asset, for its lifetime or per period.
+//! Once a payee reaches its cap, its share of further income is redistributed to the payees
+//! still below their cap, in proportion to their shares. Direct credits and gifts are never
+//! capped.
+//!
+//! The first cap set for an asset, or the first change of shares, switches its pool to a ledger
+//! like the one of a hosted split: the entitlement accrued so far is carried over per payee, and
+//! income received afterwards is split by water-filling over the room every payee has left.
+//! Income is settled lazily, once per release call or payee change rather than on every
+//! donation, and counts towards the period it is settled in. Income no payee has room for stays
+//! in the pool until a period rolls over or a cap is raised. Views count unsettled income by
+//! walking every payee; {settle_caps} lets anyone settle it so they only read the ledger.
+
+use crate::campaign::CampaignStatus;
+use crate::splits::Asset;
+use crate::*;
+
+/// A cap on the pooled payout of a payee in one asset.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayeeCap {
+    pub amount: Balance,
+    /// Length of a period in nanoseconds, `None` for a lifetime cap.
+    pub period: Option<u64>,
+    /// Start of the first period.
+    pub start: u64,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Caps the pooled payout of `account` in Ether (`token` is `None`) or in a NEP-141
+     * `token` at `amount`, for its lifetime or per `period` (in nanoseconds) starting now.
+     * An `amount` of `None` lifts the cap. Can only be called by the current owner.
+     */
+    pub fn set_payee_cap(
+        &mut self,
+        account: AccountId,
+        token: Option<AccountId>,
+        amount: Option<Balance>,
+        period: Option<u64>,
+    ) {
+        self._only_owner();
+        self._only_releases_enabled();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        require!(period != Some(0), "PaymentSplitter: period is 0");
+
+        let key = (token.clone(), account.clone());
+        match amount {
+            Some(amount) => {
+                self._carry_over(&token);
+                self._caps.insert(
+                    &key,
+                    &PayeeCap {
+                        amount,
+                        period,
+                        start: env::block_timestamp(),
+                    },
+                );
+            }
+            None => {
+                self._settle_caps(&token);
+                self._caps.remove(&key);
+                self._cap_idle.remove(&token);
+            }
+        }
+        self._cap_period_accrued.remove(&key);
+        events::emit(
+            "payee_cap_set",
+            json!({
+                "account": account,
+                "token": token,
+                "amount": amount.map(|amount| amount.to_string()),
+                "period": period.map(|period| period.to_string()),
+            }),
+        );
+    }
+
+    /**
+     * @dev Settles the pooled income of Ether (`token` is `None`) or `token` received since the
+     * last settlement, which releases do first anyway, so views no longer have to walk the
+     * payees. Throws while a campaign has not reached its goal.
+     */
+    pub fn settle_caps(&mut self, token: Option<AccountId>) {
+        self._only_releases_enabled();
+        self._settle_caps(&token);
+    }
+
+    /**
+     * @dev Getter for the cap on the pooled payout of `account` in Ether (`token` is `None`) or
+     * in a NEP-141 `token`, if any.
+     */
+    pub fn payee_cap(&self, account: AccountId, token: Option<AccountId>) -> Option<PayeeCap> {
+        self._caps.get(&(token, account))
+    }
+
+    /**
+     * @dev Getter for how much more `account` can accrue from the pool before it hits its cap in
+     * the current period, counting income not settled yet. `None` if the payee is not capped.
+     */
+    pub fn cap_remaining(&self, account: AccountId, token: Option<AccountId>) -> Option<Balance> {
+        let key = (token.clone(), account.clone());
+        let room: Balance = self._cap_room(&key)?;
+        let received: Balance = self._pool_received(&token);
+        let unsettled: Balance =
+            received.saturating_sub(self._cap_allocated.get(&token).unwrap_or(received));
+        Some(room - Self::_amount_for(&self._cap_distribution(&token, unsettled).0, &account))
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Pooled entitlement `account` accrued out of `received` in `asset`, capped and
+     * redistributed if capping is switched on for the asset.
+     */
+    pub(crate) fn _pooled_entitlement(
+        &self,
+        asset: &Asset,
+        account: &AccountId,
+        received: Balance,
+    ) -> Balance {
+        let allocated: Balance = match self._cap_allocated.get(asset) {
+            Some(value) => value,
+            None => {
+                return math::mul_div(
+                    received,
+                    self._shares.get(account).unwrap(),
+                    self._total_shares,
+                )
+            }
+        };
+        let unsettled: Balance = received.saturating_sub(allocated);
+        let carried: Balance = self
+            ._cap_carried
+            .get(&(asset.clone(), account.clone()))
+            .unwrap_or(0);
+        if unsettled == 0 || self._is_cap_idle(asset, received) {
+            // settled already, which is what every release does first
+            return carried;
+        }
+        carried + Self::_amount_for(&self._cap_distribution(asset, unsettled).0, account)
+    }
+
+    /**
+     * @dev Settles the income `asset` received since the last settlement into the carried
+     * entitlements, if capping is switched on for the asset. Nothing is settled while a campaign
+     * holds donations in escrow. Only the first settlement after new income walks the payees;
+     * income no payee has room for is remembered as idle until the block, the income, a cap or
+     * the payees change.
+     */
+    pub(crate) fn _settle_caps(&mut self, asset: &Asset) {
+        let allocated: Balance = match self._cap_allocated.get(asset) {
+            Some(value) => value,
+            None => return,
+        };
+        if !matches!(
+            self.campaign_status(),
+            None | Some(CampaignStatus::Succeeded)
+        ) {
+            return;
+        }
+        let received: Balance = self._pool_received(asset);
+        let unsettled: Balance = received.saturating_sub(allocated);
+        if unsettled == 0 || self._is_cap_idle(asset, received) {
+            return;
+        }
+        let (amounts, dust) = self._cap_distribution(asset, unsettled);
+        let mut distributed: Balance = self._collect_dust(asset, dust);
+        for (account, amount) in amounts {
+            if amount == 0 {
+                continue;
+            }
+            let key = (asset.clone(), account);
+            let carried_x: Balance = amount + self._cap_carried.get(&key).unwrap_or(0);
+            self._cap_carried.insert(&key, &carried_x);
+            if let Some(cap) = self._caps.get(&key) {
+                if let Some(period) = cap.period {
+                    let index: u64 = (env::block_timestamp() - cap.start) / period;
+                    let used_x: Balance = amount + self._cap_period_used(&key, &cap);
+                    self._cap_period_accrued.insert(&key, &(index, used_x));
+                }
+            }
+            distributed += amount;
+        }
+        self._cap_allocated
+            .insert(asset, &(allocated + distributed));
+        if allocated + distributed < received {
+            self._cap_idle
+                .insert(asset, &(env::block_timestamp(), received));
+        }
+    }
+
+    /**
+     * @dev Whether the last settlement of `asset` left income over for which no payee had room,
+     * in this block and with `received` unchanged, so settling again would not move anything.
+     */
+    fn _is_cap_idle(&self, asset: &Asset, received: Balance) -> bool {
+        self._cap_idle.get(asset) == Some((env::block_timestamp(), received))
+    }
+
+    /**
+     * @dev Settles the carried ledger of `asset`, starting it first if needed, so caps or
+     * shares can change without touching the entitlement accrued so far.
+     */
+    pub(crate) fn _carry_over(&mut self, asset: &Asset) {
+        if self._cap_allocated.get(asset).is_none() {
+            self._start_capping(asset);
+        } else {
+            self._settle_caps(asset);
+        }
+        // the caller is about to change caps or shares
+        self._cap_idle.remove(asset);
+    }
+
+    /**
+     * @dev Switches capping on for `asset`, carrying over the entitlement every payee accrued
+     * so far under the plain `_shares` split.
+     */
+    fn _start_capping(&mut self, asset: &Asset) {
+        let received: Balance = self._pool_received(asset);
+        let mut allocated: Balance = 0;
+        for id in 1..=self._payee_count {
+            let account: AccountId = self._payee_by_id(&id);
+            let entitlement: Balance = math::mul_div(
+                received,
+                self._shares.get(&account).unwrap(),
+                self._total_shares,
+            );
+            self._cap_carried
+                .insert(&(asset.clone(), account), &entitlement);
+            allocated += entitlement;
+        }
+        if self._total_shares > 0 {
+            allocated += self._collect_dust(asset, received - allocated);
+        }
+        self._cap_allocated.insert(asset, &allocated);
+    }
+
+    /**
+     * @dev Total received into the pool of `asset`.
+     */
+    pub(crate) fn _pool_received(&self, asset: &Asset) -> Balance {
+        match asset {
+            None => self._total_received(),
+            Some(token) => self._total_received_erc(token),
+        }
+    }
+
+    /**
+     * @dev Room `account` has left below its cap in the current period, `None` if uncapped.
+     */
+    fn _cap_room(&self, key: &(Asset, AccountId)) -> Option<Balance> {
+        self._caps.get(key).map(|cap| match cap.period {
+            None => cap
+                .amount
+                .saturating_sub(self._cap_carried.get(key).unwrap_or(0)),
+            Some(_) => cap.amount.saturating_sub(self._cap_period_used(key, &cap)),
+        })
+    }
+
+    /**
+     * @dev Amount accrued towards a periodic cap in the current period.
+     */
+    fn _cap_period_used(&self, key: &(Asset, AccountId), cap: &PayeeCap) -> Balance {
+        let index: u64 = (env::block_timestamp() - cap.start) / cap.period.unwrap();
+        match self._cap_period_accrued.get(key) {
+            Some((period, used)) if period == index => used,
+            _ => 0,
+        }
+    }
+
+    /**
+     * @dev Splits `income` of `asset` over the payees by water-filling: payees whose share would
+     * exceed their room get exactly their room and drop out, and the rest is split again over
+     * the others until nobody is over their cap. Shares are rounded down and the rounding
+     * remainder is returned as dust; income nobody has room for is not part of it.
+     */
+    pub(crate) fn _cap_distribution(
+        &self,
+        asset: &Asset,
+        income: Balance,
+    ) -> (Vec<(AccountId, Balance)>, Balance) {
+        let mut open: Vec<(AccountId, Balance, Option<Balance>)> = (1..=self._payee_count)
+            .map(|id| {
+                let account: AccountId = self._payee_by_id(&id);
+                let room: Option<Balance> = self._cap_room(&(asset.clone(), account.clone()));
+                (account.clone(), self._shares.get(&account).unwrap(), room)
+            })
+            .collect();
+        let mut amounts: Vec<(AccountId, Balance)> = vec![];
+        let mut remaining: Balance = income;
+        let mut dust: Balance = 0;
+        loop {
+            let total_shares: Balance = open.iter().map(|(_, shares, _)| shares).sum();
+            if total_shares == 0 {
+                break;
+            }
+            let (full, rest): (Vec<_>, Vec<_>) = open.into_iter().partition(|(_, shares, room)| {
+                matches!(room, Some(room) if math::mul_div(remaining, *shares, total_shares) >= *room)
+            });
+            open = rest;
+            if full.is_empty() {
+                dust = remaining;
+                for (account, shares, _) in open {
+                    let amount: Balance = math::mul_div(remaining, shares, total_shares);
+                    dust -= amount;
+                    amounts.push((account, amount));
+                }
+                break;
+            }
+            for (account, _, room) in full {
+                remaining -= room.unwrap();
+                amounts.push((account, room.unwrap()));
+            }
+        }
+        (amounts, dust)
+    }
+
+    pub(crate) fn _amount_for(amounts: &[(AccountId, Balance)], account: &AccountId) -> Balance {
+        amounts
+            .iter()
+            .find(|(payee, _)| payee == account)
+            .map(|(_, amount)| *amount)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    /// Payee with 4 shares, next to {bob} with 1 and {charlie} with 3.
+    fn dave() -> AccountId {
+        accounts(3)
+    }
+
+    /// A splitter paying {bob}, {charlie} and {dave} with lifetime caps of `bob_cap` and
+    /// `charlie_cap` Ether.
+    fn capped(bob_cap: Balance, charlie_cap: Balance) -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.add_payee(dave(), 4, "dave".to_string(), "near".to_string(), dave());
+        contract.set_payee_cap(bob(), None, Some(bob_cap), None);
+        contract.set_payee_cap(charlie(), None, Some(charlie_cap), None);
+        contract
+    }
+
+    /// Donates 8 NEAR and returns the income it brought into the pool.
+    fn donate(contract: &mut PaymentSplitter) -> Balance {
+        let received: Balance = contract._pool_received(&None);
+        set_context(context(owner()).attached_deposit(8 * ONE_NEAR));
+        contract.donate(None, None);
+        set_context(&context(owner()));
+        contract._pool_received(&None) - received
+    }
+
+    #[test]
+    fn payees_hitting_their_caps_at_once_are_redistributed() {
+        let mut contract: PaymentSplitter = capped(ONE_NEAR / 10, ONE_NEAR / 10);
+        let income: Balance = donate(&mut contract);
+        for _ in 0..2 {
+            assert_eq!(contract.releasable(&bob()), ONE_NEAR / 10);
+            assert_eq!(contract.releasable(&charlie()), ONE_NEAR / 10);
+            assert_eq!(contract.releasable(&dave()), income - ONE_NEAR / 5);
+            assert_eq!(contract.cap_remaining(bob(), None), Some(0));
+            // the same once settled
+            contract.settle_caps(None);
+        }
+    }
+
+    #[test]
+    fn redistribution_can_push_a_payee_over_its_cap() {
+        // bob is over his cap in the first round, charlie only in the second
+        let mut contract: PaymentSplitter = capped(ONE_NEAR / 2, 32 * ONE_NEAR / 10);
+        let income: Balance = donate(&mut contract);
+        assert_eq!(contract.releasable(&bob()), ONE_NEAR / 2);
+        assert_eq!(contract.releasable(&charlie()), 32 * ONE_NEAR / 10);
+        assert_eq!(contract.releasable(&dave()), income - 37 * ONE_NEAR / 10);
+    }
+
+    #[test]
+    fn rounding_remainder_is_dust() {
+        let contract: PaymentSplitter = capped(100, 10 * ONE_NEAR);
+        let (amounts, dust) = contract._cap_distribution(&None, 1000);
+        // bob's room is 100 of his 125, the other 900 are split 3:4 and rounded down
+        assert_eq!(amounts, vec![(bob(), 100), (charlie(), 385), (dave(), 514)]);
+        assert_eq!(dust, 1);
+    }
+
+    #[test]
+    fn cap_below_the_released_amount_stops_further_payouts() {
+        let mut contract: PaymentSplitter = splitter();
+        let first: Balance = donate(&mut contract);
+        contract.release(bob());
+        assert_eq!(contract.released(&bob()), first / 4);
+
+        contract.set_payee_cap(bob(), None, Some(first / 8), None);
+        assert_eq!(contract.cap_remaining(bob(), None), Some(0));
+        let second: Balance = donate(&mut contract);
+        assert_eq!(contract.releasable(&bob()), 0);
+        assert_eq!(contract.releasable(&charlie()), first * 3 / 4 + second);
+        contract.settle_caps(None);
+        assert_eq!(contract.releasable(&bob()), 0);
+        assert_eq!(contract.cap_remaining(bob(), None), Some(0));
+    }
+}
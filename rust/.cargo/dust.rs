@@ -0,0 +1,195 @@
+//! Rounding remainders of the shared pool.
+//!
+//! Splitting the pool rounds every payee's entitlement down, which leaves a remainder of less
+//! than one unit per payee. How that dust is handled is set by the {DustPolicy}: it can stay in
+//! the pool and be split again with later income, be assigned to a designated payee, or be set
+//! aside for a treasury and swept there. Collecting dust moves the asset to the carried ledger
+//! (see `caps.rs`), so the amounts taken out of the pool stay out of it.
+
+use crate::splits::Asset;
+use crate::*;
+
+const GAS_FOR_DUST_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DustPolicy {
+    /// Leave dust in the pool, it is split again with the next income.
+    CarryForward,
+    /// Add dust to the entitlement of `payee`.
+    AssignToPayee { payee: AccountId },
+    /// Set dust aside and let anyone sweep it to `treasury`.
+    SweepToTreasury { treasury: AccountId },
+}
+
+/// Dust collected from the pool of one asset so far.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DustLedger {
+    /// Assigned to the designated payee.
+    pub assigned: Balance,
+    /// Set aside for the treasury and not swept yet.
+    pub held: Balance,
+    /// Swept to the treasury.
+    pub swept: Balance,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets how dust is handled from now on. Dust already collected is not affected.
+     * Can only be called by the current owner.
+     */
+    pub fn set_dust_policy(&mut self, policy: DustPolicy) {
+        self._only_owner();
+        if let DustPolicy::AssignToPayee { payee } = &policy {
+            require!(
+                self._shares.get(payee).unwrap_or(0) > 0,
+                "PaymentSplitter: account has no shares"
+            );
+        }
+        self._dust_policy = policy;
+    }
+
+    /**
+     * @dev Getter for the dust policy.
+     */
+    pub fn dust_policy(&self) -> DustPolicy {
+        self._dust_policy.clone()
+    }
+
+    /**
+     * @dev Getter for the rounding remainder currently left in the pool of Ether (`token` is
+     * `None`) or of a NEP-141 `token`.
+     */
+    pub fn dust(&self, token: Option<AccountId>) -> Balance {
+        let received: Balance = self._pool_received(&token);
+        match self._cap_allocated.get(&token) {
+            Some(allocated) => {
+                self._cap_distribution(&token, received.saturating_sub(allocated))
+                    .1
+            }
+            None if self._total_shares == 0 => 0,
+            None => {
+                received
+                    - (1..=self._payee_count)
+                        .map(|id| {
+                            let shares: Balance =
+                                self._shares.get(&self._payee_by_id(&id)).unwrap();
+                            math::mul_div(received, shares, self._total_shares)
+                        })
+                        .sum::<Balance>()
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the dust collected so far from the pool of Ether (`token` is `None`) or
+     * of a NEP-141 `token`.
+     */
+    pub fn dust_ledger(&self, token: Option<AccountId>) -> DustLedger {
+        self._dust.get(&token).unwrap_or_default()
+    }
+
+    /**
+     * @dev Applies the dust policy to the current rounding remainder of Ether (`token` is
+     * `None`) or of a NEP-141 `token`.
+     */
+    pub fn collect_dust(&mut self, token: Option<AccountId>) {
+        self._only_releases_enabled();
+        self._carry_over(&token);
+    }
+
+    /**
+     * @dev Sends the dust held for the treasury in Ether (`token` is `None`) or in a NEP-141
+     * `token` to the treasury of the current {DustPolicy}.
+     */
+    pub fn sweep_dust(&mut self, token: Option<AccountId>) {
+        let treasury: AccountId = match &self._dust_policy {
+            DustPolicy::SweepToTreasury { treasury } => treasury.clone(),
+            _ => env::panic_str("PaymentSplitter: dust policy does not sweep"),
+        };
+        let mut ledger: DustLedger = self.dust_ledger(token.clone());
+        let amount: Balance = ledger.held;
+        require!(amount > 0, "PaymentSplitter: no dust to sweep");
+        ledger.held = 0;
+        ledger.swept += amount;
+        self._dust.insert(&token, &ledger);
+
+        let callback = Self::ext(env::current_account_id())
+            .with_static_gas(GAS_FOR_DUST_CALLBACK)
+            .on_dust_swept(token.clone(), amount);
+        match &token {
+            None => Promise::new(treasury.clone())
+                .transfer(amount)
+                .then(callback),
+            Some(token) => ext_ft::ext(token.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(treasury.to_string(), amount.to_string(), None)
+                .then(callback),
+        };
+        events::emit(
+            "dust_swept",
+            json!({
+                "token": token,
+                "to": treasury,
+                "amount": amount.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves a dust sweep, holding the dust again if the transfer failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_dust_swept(&mut self, token: Option<AccountId>, amount: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Sweeping {} dust failed, dust held again", amount);
+                let mut ledger: DustLedger = self.dust_ledger(token.clone());
+                ledger.swept -= amount;
+                ledger.held += amount;
+                self._dust.insert(&token, &ledger);
+                false
+            }
+        }
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Getter for the dust of `asset` swept to the treasury, which counts as paid out of the
+     * pool like released funds.
+     */
+    pub(crate) fn _dust_swept(&self, asset: &Asset) -> Balance {
+        self._dust
+            .get(asset)
+            .map(|ledger| ledger.swept)
+            .unwrap_or(0)
+    }
+
+    /**
+     * @dev Applies the dust policy to `dust` of `asset` and returns the part taken out of the
+     * pool, which the caller books as allocated.
+     */
+    pub(crate) fn _collect_dust(&mut self, asset: &Asset, dust: Balance) -> Balance {
+        if dust == 0 {
+            return 0;
+        }
+        let mut ledger: DustLedger = self._dust.get(asset).unwrap_or_default();
+        match &self._dust_policy {
+            DustPolicy::CarryForward => return 0,
+            DustPolicy::AssignToPayee { payee } => {
+                let key = (asset.clone(), payee.clone());
+                let carried_x: Balance = dust + self._cap_carried.get(&key).unwrap_or(0);
+                self._cap_carried.insert(&key, &carried_x);
+                ledger.assigned += dust;
+            }
+            DustPolicy::SweepToTreasury { .. } => ledger.held += dust,
+        }
+        self._dust.insert(asset, &ledger);
+        dust
+    }
+}
@@ -0,0 +1,32 @@
+//! NEP-297 events emitted by the PaymentSplitter.
+//!
+//! Each event is logged as `EVENT_JSON:{"standard":"paymentsplitter","version":"1.0.0",...}`
+//! so indexers can pick them up the same way as Solidity events.
+
+use near_sdk::log;
+use near_sdk::serde_json::{json, Value};
+
+const EVENT_STANDARD: &str = "paymentsplitter";
+const EVENT_VERSION: &str = "1.0.0";
+
+/**
+ * @dev Logs `event` with a single `data` entry in the NEP-297 format.
+ */
+pub(crate) fn emit(event: &str, data: Value) {
+    emit_with_standard(EVENT_STANDARD, EVENT_VERSION, event, data);
+}
+
+/**
+ * @dev Logs `event` under another NEP standard, e.g. `nep171` for receipt mints.
+ */
+pub(crate) fn emit_with_standard(standard: &str, version: &str, event: &str, data: Value) {
+    log!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": standard,
+            "version": version,
+            "event": event,
+            "data": [data],
+        })
+    );
+}
@@ -0,0 +1,265 @@
+//! Platform fee on incoming donations.
+//!
+//! With a {PlatformFee} set, `fee_bps` basis points of every donation, in Ether or in a NEP-141
+//! token and to the default split or a hosted one, are taken before anything else: only the
+//! rest is pooled, allocated, gifted, received by the hosted split or contributed to a
+//! campaign. Fees are held for the treasury, apart from payee entitlements, and anyone can
+//! release them to it. The fee on a campaign contribution is reversed if it is refunded, so
+//! campaign fees can only be released once the campaign succeeded.
+
+use crate::splits::Asset;
+use crate::*;
+
+/// Highest fee that can be set, 10%.
+pub const MAX_PLATFORM_FEE_BPS: u32 = 1_000;
+
+const GAS_FOR_FEE_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PlatformFee {
+    /// Part of every donation taken as fee, in basis points of the donated amount.
+    pub fee_bps: u32,
+    /// Account the fees are released to.
+    pub treasury: AccountId,
+}
+
+/// Fees taken from donations of one asset so far.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeLedger {
+    /// Taken and not released to the treasury yet.
+    pub held: Balance,
+    /// Released to the treasury.
+    pub released: Balance,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the platform fee taken from donations from now on, or removes it if `fee` is
+     * `None`. Fees already taken stay held for release to the treasury of the current fee.
+     * Can only be called by the current owner.
+     */
+    pub fn set_platform_fee(&mut self, fee: Option<PlatformFee>) {
+        self._only_owner();
+        Self::_require_valid_fee(&fee);
+        self._platform_fee = fee;
+        events::emit(
+            "platform_fee_set",
+            json!({
+                "fee_bps": self._platform_fee.as_ref().map(|fee| fee.fee_bps),
+                "treasury": self._platform_fee.as_ref().map(|fee| fee.treasury.clone()),
+            }),
+        );
+    }
+
+    /**
+     * @dev Getter for the platform fee, if any.
+     */
+    pub fn platform_fee(&self) -> Option<PlatformFee> {
+        self._platform_fee.clone()
+    }
+
+    /**
+     * @dev Getter for the fees taken so far from donations in Ether (`token` is `None`) or in a
+     * NEP-141 `token`.
+     */
+    pub fn fee_ledger(&self, token: Option<AccountId>) -> FeeLedger {
+        self._fees.get(&token).unwrap_or_default()
+    }
+
+    /**
+     * @dev Sends the fees held in Ether (`token` is `None`) or in a NEP-141 `token` to the
+     * treasury of the current {PlatformFee}. Throws while a campaign has not reached its goal,
+     * as the fees of its contributions may still be reversed.
+     */
+    pub fn release_fees(&mut self, token: Option<AccountId>) {
+        self._only_releases_enabled();
+        let treasury: AccountId = self
+            ._platform_fee
+            .as_ref()
+            .map(|fee| fee.treasury.clone())
+            .expect("PaymentSplitter: no platform fee");
+        let mut ledger: FeeLedger = self.fee_ledger(token.clone());
+        let amount: Balance = ledger.held;
+        require!(amount > 0, "PaymentSplitter: no fees to release");
+        ledger.held = 0;
+        ledger.released += amount;
+        self._fees.insert(&token, &ledger);
+
+        let callback = Self::ext(env::current_account_id())
+            .with_static_gas(GAS_FOR_FEE_CALLBACK)
+            .on_fees_released(token.clone(), amount);
+        match &token {
+            None => Promise::new(treasury.clone())
+                .transfer(amount)
+                .then(callback),
+            Some(token) => ext_ft::ext(token.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(treasury.to_string(), amount.to_string(), None)
+                .then(callback),
+        };
+        events::emit(
+            "fees_released",
+            json!({
+                "token": token,
+                "to": treasury,
+                "amount": amount.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves a fee release, holding the fees again if the transfer failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_fees_released(&mut self, token: Option<AccountId>, amount: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Releasing {} in fees failed, fees held again", amount);
+                let mut ledger: FeeLedger = self.fee_ledger(token.clone());
+                ledger.released -= amount;
+                ledger.held += amount;
+                self._fees.insert(&token, &ledger);
+                false
+            }
+        }
+    }
+}
+
+impl PaymentSplitter {
+    pub(crate) fn _require_valid_fee(fee: &Option<PlatformFee>) {
+        if let Some(fee) = fee {
+            require!(
+                fee.fee_bps <= MAX_PLATFORM_FEE_BPS,
+                "PaymentSplitter: platform fee is too high"
+            );
+        }
+    }
+
+    /**
+     * @dev Getter for all fees ever taken from donations of `asset`, which are not part of the
+     * pool.
+     */
+    pub(crate) fn _fees_taken(&self, asset: &Asset) -> Balance {
+        self._fees
+            .get(asset)
+            .map(|ledger| ledger.held + ledger.released)
+            .unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the fees of `asset` released to the treasury, which count as paid out like
+     * released funds.
+     */
+    pub(crate) fn _fees_released(&self, asset: &Asset) -> Balance {
+        self._fees
+            .get(asset)
+            .map(|ledger| ledger.released)
+            .unwrap_or(0)
+    }
+
+    /**
+     * @dev Reverses a platform fee of `fee` in `asset` taken from a refunded campaign
+     * contribution.
+     */
+    pub(crate) fn _reverse_platform_fee(&mut self, asset: &Asset, fee: Balance) {
+        if fee == 0 {
+            return;
+        }
+        let mut ledger: FeeLedger = self._fees.get(asset).unwrap_or_default();
+        ledger.held -= fee;
+        self._fees.insert(asset, &ledger);
+    }
+
+    /**
+     * @dev Takes the platform fee out of a donation of `amount` of `asset` by `donor` and
+     * returns what is left for the split.
+     */
+    pub(crate) fn _take_platform_fee(
+        &mut self,
+        donor: &AccountId,
+        asset: &Asset,
+        amount: Balance,
+    ) -> Balance {
+        let fee_bps: u32 = match &self._platform_fee {
+            Some(fee) => fee.fee_bps,
+            None => return amount,
+        };
+        let fee: Balance =
+            math::mul_div(amount, fee_bps as Balance, basis_points::TOTAL_BASIS_POINTS);
+        if fee == 0 {
+            return amount;
+        }
+        let mut ledger: FeeLedger = self._fees.get(asset).unwrap_or_default();
+        ledger.held += fee;
+        self._fees.insert(asset, &ledger);
+        events::emit(
+            "platform_fee_taken",
+            json!({
+                "token": asset,
+                "from": donor,
+                "amount": amount.to_string(),
+                "fee": fee.to_string(),
+            }),
+        );
+        amount - fee
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    fn fee(fee_bps: u32) -> Option<PlatformFee> {
+        Some(PlatformFee {
+            fee_bps,
+            treasury: accounts(4),
+        })
+    }
+
+    #[test]
+    fn fee_is_taken_up_to_the_cap() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_platform_fee(fee(MAX_PLATFORM_FEE_BPS));
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate(None, None);
+        assert_eq!(contract.fee_ledger(None).held, ONE_NEAR / 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: platform fee is too high")]
+    fn fee_above_the_cap_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_platform_fee(fee(MAX_PLATFORM_FEE_BPS + 1));
+    }
+
+    #[test]
+    fn fee_is_reversed_when_a_contribution_is_refunded() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_platform_fee(fee(MAX_PLATFORM_FEE_BPS));
+        contract.start_campaign(100 * ONE_NEAR, 1_000);
+        set_context(context(bob()).attached_deposit(ONE_NEAR));
+        contract.donate(None, None);
+        assert_eq!(contract.fee_ledger(None).held, ONE_NEAR / 10);
+        let contribution: Balance = contract.campaign_contribution(&bob());
+
+        set_context(context(bob()).block_timestamp(1_001));
+        contract.claim_refund();
+        assert_eq!(contract.fee_ledger(None).held, 0);
+        assert_eq!(transfers(), vec![(bob(), contribution)]);
+
+        // a failed refund takes the fee again
+        set_callback_context(
+            context(splitter_account()).block_timestamp(1_001),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.on_refund_complete(bob(), None, contribution, ONE_NEAR / 10));
+        assert_eq!(contract.fee_ledger(None).held, ONE_NEAR / 10);
+    }
+}
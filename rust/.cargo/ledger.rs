@@ -0,0 +1,217 @@
+//! Explicit ledger of the Ether and tokens the splitter received.
+//!
+//! Entitlements are computed from the donations booked through the donation entry points
+//! ({donate} and friends for Ether, `ft_on_transfer` for tokens), not from balances, so
+//! attached deposits, storage staking and gas refunds never count as income and token views
+//! need no cross-contract call. {reconcile} compares the Ether ledger with the actual balance,
+//! {sync_token_balance} fetches a token balance to do the same for a token. Funds that arrive
+//! without going through a donation entry point, like bare transfers, show up as surplus and
+//! are only split once the owner sweeps them into the pool with {sweep_surplus}.
+
+use crate::splits::Asset;
+use crate::*;
+
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_SYNC_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+/// Contract balance against the funds the ledger accounts for, as returned by {reconcile}
+/// and, for tokens, by {reconcile_erc}.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reconciliation {
+    /// Current contract balance, for Ether including the NEAR held as wNEAR and the NEAR
+    /// delegated to the staking pool.
+    pub balance: Balance,
+    /// Part of the balance staked for the contract's storage, always 0 for tokens.
+    pub storage_reserved: Balance,
+    /// Received and not paid out yet: pooled, credited and gifted funds, hosted splits, and
+    /// dust and platform fees held for the treasury.
+    pub accounted: Balance,
+    /// Balance on top of storage and accounted funds, e.g. deposits attached to calls.
+    pub surplus: Balance,
+    /// Accounted funds and storage the balance does not cover. Should always be 0.
+    pub shortfall: Balance,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Getter for the total Ether donated to the default split, including platform fees (also
+     * those taken from donations to hosted splits), credits and gifts to single payees and
+     * staking rewards, and excluding refunded campaign contributions.
+     */
+    pub fn total_donated(&self) -> Balance {
+        self._total_donated
+    }
+
+    /**
+     * @dev Getter for the total amount of `token` donated to the default split, the token
+     * counterpart of {total_donated}.
+     */
+    pub fn total_donated_erc(&self, token: &AccountId) -> Balance {
+        self._erc20_donated.get(token).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for a reconciliation of the contract balance with the ledger.
+     */
+    pub fn reconcile(&self) -> Reconciliation {
+        let balance: Balance = env::account_balance()
+            + self._wrapped_held
+            + self._unwrapping
+            + self._staked
+            + self._unstaking;
+        let storage_reserved: Balance = env::storage_usage() as Balance * env::storage_byte_cost();
+        let accounted: Balance = self._accounted(&None);
+        let covered: Balance = storage_reserved + accounted;
+        Reconciliation {
+            balance,
+            storage_reserved,
+            accounted,
+            surplus: balance.saturating_sub(covered),
+            shortfall: covered.saturating_sub(balance),
+        }
+    }
+
+    /**
+     * @dev Getter for a reconciliation of the `token` balance fetched by the last
+     * {sync_token_balance} with the ledger, `None` if the balance was never synced.
+     */
+    pub fn reconcile_erc(&self, token: AccountId) -> Option<Reconciliation> {
+        let balance: Balance = self._erc20_synced_balance.get(&token)?;
+        let accounted: Balance = self._accounted(&Some(token));
+        Some(Reconciliation {
+            balance,
+            storage_reserved: 0,
+            accounted,
+            surplus: balance.saturating_sub(accounted),
+            shortfall: accounted.saturating_sub(balance),
+        })
+    }
+
+    /**
+     * @dev Fetches the contract's balance of `token` from the token contract and stores it for
+     * {reconcile_erc}. Entitlements never depend on it.
+     */
+    pub fn sync_token_balance(&mut self, token: AccountId) -> Promise {
+        ext_ft::ext(token.clone())
+            .with_static_gas(GAS_FOR_FT_BALANCE_OF)
+            .ft_balance_of(env::current_account_id().to_string())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_SYNC_CALLBACK)
+                    .on_token_balance(token),
+            )
+    }
+
+    /**
+     * @dev Stores the balance fetched by {sync_token_balance} and returns the reconciliation.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_token_balance(&mut self, token: AccountId) -> Option<Reconciliation> {
+        let balance: U128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                match near_sdk::serde_json::from_slice::<U128>(&value) {
+                    Ok(balance) => balance,
+                    Err(_) => {
+                        log!("Invalid ft_balance_of result from {}", token);
+                        return None;
+                    }
+                }
+            }
+            _ => {
+                log!("There was an error contacting NEP-141 {}", token);
+                return None;
+            }
+        };
+        self._erc20_synced_balance.insert(&token, &balance.0);
+        let reconciliation: Option<Reconciliation> = self.reconcile_erc(token.clone());
+        if let Some(reconciliation) = &reconciliation {
+            events::emit(
+                "token_balance_synced",
+                json!({
+                    "token": token,
+                    "balance": balance,
+                    "surplus": reconciliation.surplus.to_string(),
+                    "shortfall": reconciliation.shortfall.to_string(),
+                }),
+            );
+        }
+        reconciliation
+    }
+
+    /**
+     * @dev Books the surplus of Ether (`token` is `None`) or of a NEP-141 `token` as pooled
+     * income, so it is split like a donation. For a token, the surplus is taken from the balance
+     * fetched by the last {sync_token_balance}, which has to be synced while no transfer of the
+     * token is in flight. Returns the amount swept. Can only be called by the current owner.
+     */
+    pub fn sweep_surplus(&mut self, token: Option<AccountId>) -> U128 {
+        self._only_owner();
+        require!(
+            self._campaign.is_none(),
+            "PaymentSplitter: cannot sweep during a campaign"
+        );
+        let surplus: Balance = match &token {
+            None => self.reconcile().surplus,
+            Some(token) => {
+                self.reconcile_erc(token.clone())
+                    .expect("PaymentSplitter: token balance was never synced")
+                    .surplus
+            }
+        };
+        require!(surplus > 0, "PaymentSplitter: no surplus to sweep");
+        self._book_donated(&token, surplus);
+        events::emit(
+            "surplus_swept",
+            json!({
+                "token": token,
+                "amount": surplus.to_string(),
+            }),
+        );
+        U128(surplus)
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Funds of `asset` received and not paid out yet, see {Reconciliation}.
+     */
+    pub(crate) fn _accounted(&self, asset: &Asset) -> Balance {
+        let (donated, held, released) = match asset {
+            None => (self._total_donated, self._splits_held, self._total_released),
+            Some(token) => (
+                self.total_donated_erc(token),
+                self.splits_held(Some(token.clone())),
+                self.total_released_erc(token),
+            ),
+        };
+        donated + held - released - self._dust_swept(asset) - self._fees_released(asset)
+    }
+
+    /**
+     * @dev Books `amount` of `asset` donated to the default split.
+     */
+    pub(crate) fn _book_donated(&mut self, asset: &Asset, amount: Balance) {
+        match asset {
+            None => self._total_donated += amount,
+            Some(token) => {
+                let donated_x: Balance = amount + self.total_donated_erc(token);
+                self._erc20_donated.insert(token, &donated_x);
+            }
+        }
+    }
+
+    /**
+     * @dev Takes a refunded campaign contribution of `amount` of `asset` back out of the ledger.
+     */
+    pub(crate) fn _book_refunded(&mut self, asset: &Asset, amount: Balance) {
+        match asset {
+            None => self._total_donated -= amount,
+            Some(token) => {
+                let donated_x: Balance = self.total_donated_erc(token) - amount;
+                self._erc20_donated.insert(token, &donated_x);
+            }
+        }
+    }
+}
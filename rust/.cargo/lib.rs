@@ -4,18 +4,65 @@
 //! [function_name]: description of function
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{UnorderedMap, Vector};
+use near_sdk::collections::{UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
 use near_sdk::{
-    env, log, near_bindgen, require, AccountId, Balance, PanicOnDefault, PromiseResult,
+    env, log, near_bindgen, require, AccountId, Balance, Gas, PanicOnDefault, PromiseOrValue,
+    PromiseResult,
 };
-//use near_sdk::json_types::U128;
+use std::collections::HashMap;
 use near_sdk::ext_contract;
 use near_sdk::Promise;
 
+mod audit;
+mod basis_points;
+mod campaign;
+mod caps;
+mod dust;
+mod events;
+mod fees;
+mod ledger;
+mod math;
+mod migration;
+mod nested;
+mod payee_spec;
+mod receipts;
+mod schedule;
+mod splits;
+mod staking;
+mod swap;
+#[cfg(test)]
+mod testing;
+mod thresholds;
+mod vesting;
+mod wnear;
+
+use basis_points::StagedApply;
+use campaign::Campaign;
+use caps::PayeeCap;
+use dust::{DustLedger, DustPolicy};
+use fees::{FeeLedger, PlatformFee};
+pub use payee_spec::PayeeSpec;
+use receipts::Receipt;
+use schedule::{ReleaseRound, ReleaseSchedule};
+use splits::Split;
+use staking::StakingConfig;
+use swap::{SwapConfig, SwapPool};
+use vesting::VestingSchedule;
+
 #[ext_contract(ext_ft)]
 pub trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: String, amount: String, memo: Option<String>);
-    fn ft_balance_of(&self, account_id: String) -> Balance;
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: String,
+        amount: String,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+    fn ft_balance_of(&self, account_id: String) -> U128;
 }
 
 // #[ext_contract(ext_fungible_token)]
@@ -39,6 +86,79 @@ pub trait FungibleToken {
 
 //near_sdk::setup_alloc!();
 
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_RELEASE_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// Time the owner has to wait between requesting and executing a recovery (7 days in nanoseconds)
+const RECOVERY_DELAY: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_MEMO_LENGTH: usize = 256;
+/// Largest payee batch {add_payees} accepts, larger rosters are imported in chunks
+const MAX_PAYEES_PER_CALL: usize = 100;
+
+/// Amounts credited directly to payees, keyed by payee ID, instead of following the `_shares` split.
+pub type Allocation = HashMap<u128, Balance>;
+
+/// `allocation` with string amounts for events, as JSON numbers cannot hold a u128.
+fn allocation_json(allocation: &Option<Allocation>) -> Option<HashMap<String, U128>> {
+    allocation.as_ref().map(|allocation| {
+        allocation
+            .iter()
+            .map(|(id, value)| (id.to_string(), U128(*value)))
+            .collect()
+    })
+}
+
+/// A donation made through {donate}, {donate_to_payee}, {split_donate} or {ft_on_transfer}, kept
+/// for attribution and reporting. `token` is `None` for Ether (NEAR) donations, `payee_id` is set
+/// for gifts to a single payee and `split_id` for donations to a hosted split.
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Donation {
+    pub donor: AccountId,
+    pub token: Option<AccountId>,
+    pub payee_id: Option<u128>,
+    pub split_id: Option<String>,
+    pub amount: Balance,
+    pub timestamp: u64,
+    pub memo: Option<String>,
+}
+
+/// A payee as returned by {export_payees}. Apart from `id`, the fields match {PayeeSpec}, so an
+/// export can be fed back into {add_payees}.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayeeRecord {
+    pub id: u128,
+    pub account: AccountId,
+    pub shares: Balance,
+    pub name: String,
+    pub chain: String,
+    pub wallet: String,
+    pub splitter: bool,
+}
+
+/// Optional settings applied by {new}. Everything left out keeps its default.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SplitterConfig {
+    pub receipt_threshold: Option<Balance>,
+    /// Start in basis point mode, see {set_basis_points_mode}.
+    #[serde(default)]
+    pub basis_points: bool,
+    /// Platform fee taken from donations, see {set_platform_fee}.
+    pub platform_fee: Option<PlatformFee>,
+}
+
+/// JSON payload accepted in the `msg` of `ft_transfer_call`. An empty `msg` is a plain donation.
+/// `allocation`, `payee_id` and `split_id` are mutually exclusive.
+#[derive(Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DonationMsg {
+    pub memo: Option<String>,
+    pub allocation: Option<Allocation>,
+    pub payee_id: Option<u128>,
+    pub split_id: Option<String>,
+}
+
 // add the following attributes to prepare your code for serialization and invocation on the blockchain
 // More built-in Rust attributes here: https://doc.rust-lang.org/reference/attributes.html#built-in-attributes-index
 #[near_bindgen]
@@ -49,8 +169,6 @@ pub struct PaymentSplitter {
     _total_shares: Balance,
     _total_released: Balance,
 
-    _balance_of: Balance,
-
     _payees2: Vector<u128>,
     _payees: Vector<AccountId>,
 
@@ -63,6 +181,91 @@ pub struct PaymentSplitter {
 
     _erc20_released: UnorderedMap<AccountId, UnorderedMap<AccountId, Balance>>,
     _erc20_total_released: UnorderedMap<AccountId, Balance>,
+
+    _backup_beneficiary: UnorderedMap<AccountId, AccountId>,
+    _inactivity_period: UnorderedMap<AccountId, u64>,
+    _last_activity: UnorderedMap<AccountId, u64>,
+    _release_failed: UnorderedMap<AccountId, bool>,
+    _recovery_requested_at: UnorderedMap<AccountId, u64>,
+
+    _donations: Vector<Donation>,
+    _donor_total: UnorderedMap<AccountId, Balance>,
+
+    _total_credited: Balance,
+    _credited: UnorderedMap<AccountId, Balance>,
+    _erc20_credited: UnorderedMap<AccountId, UnorderedMap<AccountId, Balance>>,
+    _erc20_total_credited: UnorderedMap<AccountId, Balance>,
+
+    _receipt_threshold: Option<Balance>,
+    _erc20_receipt_threshold: UnorderedMap<AccountId, Balance>,
+    _receipts: UnorderedMap<String, Receipt>,
+    _receipts_per_owner: UnorderedMap<AccountId, UnorderedSet<String>>,
+
+    _campaign: Option<Campaign>,
+    _campaign_contributions: UnorderedMap<AccountId, Balance>,
+    _campaign_erc20_contributions: UnorderedMap<AccountId, UnorderedMap<AccountId, Balance>>,
+    _campaign_erc20_raised: UnorderedMap<AccountId, Balance>,
+
+    _splits: UnorderedMap<String, Split>,
+    _splits_held: Balance,
+    _splits_erc20_held: UnorderedMap<AccountId, Balance>,
+
+    _payee_is_splitter: UnorderedMap<AccountId, bool>,
+
+    _caps: UnorderedMap<(Option<AccountId>, AccountId), PayeeCap>,
+    _cap_allocated: UnorderedMap<Option<AccountId>, Balance>,
+    _cap_carried: UnorderedMap<(Option<AccountId>, AccountId), Balance>,
+    _cap_period_accrued: UnorderedMap<(Option<AccountId>, AccountId), (u64, Balance)>,
+
+    _basis_points: bool,
+
+    _dust_policy: DustPolicy,
+    _dust: UnorderedMap<Option<AccountId>, DustLedger>,
+
+    _total_donated: Balance,
+    _erc20_donated: UnorderedMap<AccountId, Balance>,
+    _erc20_synced_balance: UnorderedMap<AccountId, Balance>,
+
+    _vesting: UnorderedMap<AccountId, VestingSchedule>,
+
+    _release_schedule: Option<ReleaseSchedule>,
+    _last_release_round: u64,
+
+    _payout_thresholds: UnorderedMap<(Option<AccountId>, Option<AccountId>), Balance>,
+
+    _platform_fee: Option<PlatformFee>,
+    _fees: UnorderedMap<Option<AccountId>, FeeLedger>,
+
+    _swap_config: Option<SwapConfig>,
+    _swap_pools: UnorderedMap<(AccountId, AccountId), SwapPool>,
+    _payout_token: UnorderedMap<AccountId, AccountId>,
+
+    _wrap_contract: Option<AccountId>,
+    _wrapped_held: Balance,
+    _payout_wrapped: UnorderedMap<AccountId, bool>,
+
+    _staking: Option<StakingConfig>,
+    _staked: Balance,
+    _unstaking: Balance,
+    _unstaked_epoch: u64,
+
+    _accepted_tokens: UnorderedMap<AccountId, Balance>,
+
+    _cap_idle: UnorderedMap<Option<AccountId>, (u64, Balance)>,
+
+    _staged_split: UnorderedMap<AccountId, PayeeSpec>,
+
+    _release_all_cursor: u128,
+    _release_round: Option<ReleaseRound>,
+
+    _campaign_fees: UnorderedMap<(Option<AccountId>, AccountId), Balance>,
+
+    _unwrapping: Balance,
+
+    _campaign_erc20_unclaimed: UnorderedMap<AccountId, Balance>,
+
+    _staged_shares: Balance,
+    _staged_apply: Option<StagedApply>,
 }
 
 // mapping(IERC20 => uint256) private _erc20TotalReleased;
@@ -72,18 +275,56 @@ pub struct PaymentSplitter {
 impl PaymentSplitter {
     /// Contract Initializer
     /// Behaves like a constructor in solidity
+    ///
+    /// Sets `owner` (the caller by default), applies `config` and registers the initial
+    /// `payees` in one go. The whole initialization fails if a payee is listed twice or has
+    /// zero shares.
     #[init]
-    pub fn new() -> Self {
+    pub fn new(
+        owner: Option<AccountId>,
+        payees: Option<Vec<PayeeSpec>>,
+        config: Option<SplitterConfig>,
+    ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         log!("PaymentSplitter Initialized!");
+        let config: SplitterConfig = config.unwrap_or_default();
+        Self::_require_valid_fee(&config.platform_fee);
+        let mut this = Self::_empty(owner.unwrap_or_else(env::predecessor_account_id), config);
+        migration::record_state_version();
+        // nothing has been received yet, so payees are added without the release_all of _add_payee
+        for payee in payees.unwrap_or_default() {
+            this._require_new_payee(&payee.account, payee.shares);
+            if payee.splitter {
+                this._payee_is_splitter.insert(&payee.account, &true);
+            }
+            this._insert_payee(
+                payee.account,
+                payee.shares,
+                payee.name,
+                payee.chain,
+                payee.wallet,
+            );
+        }
+        if this._basis_points && this._payee_count > 0 {
+            require!(
+                this._total_shares == basis_points::TOTAL_BASIS_POINTS,
+                "PaymentSplitter: shares do not add up to 10000 basis points"
+            );
+        }
+        this
+    }
+
+    /**
+     * @dev A splitter owned by `owner` with `config` applied and empty collections, used by
+     * {new} and, before it moves the old state in, by {migrate}.
+     */
+    fn _empty(owner: AccountId, config: SplitterConfig) -> Self {
         Self {
-            _owner: env::predecessor_account_id(),
+            _owner: owner,
             _payee_count: 0,
             _total_shares: 0,
             _total_released: 0,
 
-            _balance_of: 0,
-
             _payees: Vector::new(b"a"),
             _payees2: Vector::new(b"b"),
 
@@ -96,6 +337,91 @@ impl PaymentSplitter {
 
             _erc20_released: UnorderedMap::new(b"i"),
             _erc20_total_released: UnorderedMap::new(b"j"),
+
+            _backup_beneficiary: UnorderedMap::new(b"k"),
+            _inactivity_period: UnorderedMap::new(b"l"),
+            _last_activity: UnorderedMap::new(b"m"),
+            _release_failed: UnorderedMap::new(b"n"),
+            _recovery_requested_at: UnorderedMap::new(b"o"),
+
+            _donations: Vector::new(b"p"),
+            _donor_total: UnorderedMap::new(b"q"),
+
+            _total_credited: 0,
+            _credited: UnorderedMap::new(b"r"),
+            _erc20_credited: UnorderedMap::new(b"s"),
+            _erc20_total_credited: UnorderedMap::new(b"t"),
+
+            _receipt_threshold: config.receipt_threshold,
+            _erc20_receipt_threshold: UnorderedMap::new(b"A"),
+            _receipts: UnorderedMap::new(b"B"),
+            _receipts_per_owner: UnorderedMap::new(b"C"),
+
+            _campaign: None,
+            _campaign_contributions: UnorderedMap::new(b"E"),
+            _campaign_erc20_contributions: UnorderedMap::new(b"F"),
+            _campaign_erc20_raised: UnorderedMap::new(b"H"),
+
+            _splits: UnorderedMap::new(b"I"),
+            _splits_held: 0,
+            _splits_erc20_held: UnorderedMap::new(b"J"),
+
+            _payee_is_splitter: UnorderedMap::new(b"L"),
+
+            _caps: UnorderedMap::new(b"M"),
+            _cap_allocated: UnorderedMap::new(b"N"),
+            _cap_carried: UnorderedMap::new(b"O"),
+            _cap_period_accrued: UnorderedMap::new(b"P"),
+
+            _basis_points: config.basis_points,
+
+            _dust_policy: DustPolicy::CarryForward,
+            _dust: UnorderedMap::new(b"Q"),
+
+            _total_donated: 0,
+            _erc20_donated: UnorderedMap::new(b"R"),
+            _erc20_synced_balance: UnorderedMap::new(b"S"),
+
+            _vesting: UnorderedMap::new(b"T"),
+
+            _release_schedule: None,
+            _last_release_round: 0,
+
+            _payout_thresholds: UnorderedMap::new(b"U"),
+
+            _platform_fee: config.platform_fee,
+            _fees: UnorderedMap::new(b"V"),
+
+            _swap_config: None,
+            _swap_pools: UnorderedMap::new(b"W"),
+            _payout_token: UnorderedMap::new(b"X"),
+
+            _wrap_contract: None,
+            _wrapped_held: 0,
+            _payout_wrapped: UnorderedMap::new(b"Y"),
+
+            _staking: None,
+            _staked: 0,
+            _unstaking: 0,
+            _unstaked_epoch: 0,
+
+            _accepted_tokens: UnorderedMap::new(b"Z"),
+
+            _cap_idle: UnorderedMap::new(b"0"),
+
+            _staged_split: UnorderedMap::new(b"1"),
+
+            _release_all_cursor: 1,
+            _release_round: None,
+
+            _campaign_fees: UnorderedMap::new(b"2"),
+
+            _unwrapping: 0,
+
+            _campaign_erc20_unclaimed: UnorderedMap::new(b"3"),
+
+            _staged_shares: 0,
+            _staged_apply: None,
         }
     }
     /**
@@ -123,7 +449,7 @@ impl PaymentSplitter {
      */
     pub fn transfer_ownership(&mut self, new_owner: AccountId) {
         self._only_owner();
-        require!(new_owner != "".parse().unwrap(), "Owner is zero address");
+        require!(!new_owner.as_str().is_empty(), "Owner is zero address");
         self._transfer_ownership(new_owner);
     }
     /**
@@ -141,7 +467,7 @@ impl PaymentSplitter {
      */
     fn _only_owner(&self) {
         require!(
-            env::current_account_id() == self._owner,
+            Self::_msg_sender() == self._owner,
             "Ownable: caller is not the owner"
         );
     }
@@ -164,15 +490,377 @@ impl PaymentSplitter {
      * triggering this function. This only affects the reliability of the events, and not the
      * actual splitting of Ether.
      *
-     * To learn more about this see the Solidity documentation for
-     * https://solidity.readthedocs.io/en/latest/contracts.html#fallback-function[fallback
-     * functions].
+     * Donations made through this function are attributed to the donor, together with the
+     * timestamp and an optional `memo`, and count towards the donor's cumulative total.
+     * The storage the donation adds is paid out of the attached Ether, the rest is split.
+     */
+    #[payable]
+    pub fn donate(&mut self, memo: Option<String>, allocation: Option<Allocation>) {
+        let donor: AccountId = Self::_msg_sender();
+        let amount: Balance = env::attached_deposit();
+        require!(amount > 0, "PaymentSplitter: donation is 0");
+
+        self._receive_donation(
+            Donation {
+                donor: donor.clone(),
+                token: None,
+                payee_id: None,
+                split_id: None,
+                amount,
+                timestamp: env::block_timestamp(),
+                memo: memo.clone(),
+            },
+            allocation.as_ref(),
+        );
+
+        //emit PaymentReceived(_msgSender(), msg.value);
+        events::emit(
+            "payment_received",
+            json!({
+                "from": donor,
+                "amount": amount.to_string(),
+                "memo": memo,
+                "allocation": allocation_json(&allocation),
+            }),
+        );
+    }
+
+    /**
+     * @dev Gift of the attached Ether entirely to the payee number `payee_id`. The gift bypasses
+     * the `_shares` split, is reported separately from pooled income and is paid out through
+     * the regular {release} flow. The storage the gift adds is paid out of it.
+     */
+    #[payable]
+    pub fn donate_to_payee(&mut self, payee_id: u128, memo: Option<String>) {
+        let donor: AccountId = Self::_msg_sender();
+        let amount: Balance = env::attached_deposit();
+        require!(amount > 0, "PaymentSplitter: donation is 0");
+
+        self._receive_donation(
+            Donation {
+                donor: donor.clone(),
+                token: None,
+                payee_id: Some(payee_id),
+                split_id: None,
+                amount,
+                timestamp: env::block_timestamp(),
+                memo: memo.clone(),
+            },
+            None,
+        );
+
+        events::emit(
+            "payment_received",
+            json!({
+                "from": donor,
+                "amount": amount.to_string(),
+                "memo": memo,
+                "payee_id": payee_id.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev NEP-141 receiver, the token counterpart of {donate} and {donate_to_payee}. `msg` is
+     * either empty or a {DonationMsg} JSON carrying a memo and either an allocation over payee
+     * IDs or a single `payee_id` receiving the whole amount.
+     * All received tokens are kept; an invalid `msg` panics so the token contract refunds them.
+     * Only tokens accepted through {set_accepted_token} are taken, in amounts of at least their
+     * minimum. wNEAR is unwrapped and booked as Ether, see `wnear.rs`.
+     */
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token: AccountId = Self::_msg_sender();
+        let amount: Balance = amount.0;
+        let donation: DonationMsg = if msg.is_empty() {
+            DonationMsg::default()
+        } else {
+            near_sdk::serde_json::from_str(&msg).expect("PaymentSplitter: invalid msg")
+        };
+
+        require!(
+            [
+                donation.allocation.is_some(),
+                donation.payee_id.is_some(),
+                donation.split_id.is_some(),
+            ]
+            .iter()
+            .filter(|set| **set)
+            .count()
+                <= 1,
+            "PaymentSplitter: allocation, payee_id and split_id are mutually exclusive"
+        );
+
+        let wrapped: bool = self._is_wrapped_near(&token);
+        if !wrapped {
+            let minimum: Balance = self
+                ._accepted_tokens
+                .get(&token)
+                .expect("PaymentSplitter: token is not accepted");
+            require!(
+                amount >= minimum,
+                "PaymentSplitter: donation is below the token minimum"
+            );
+        }
+        let asset: Option<AccountId> = if wrapped { None } else { Some(token.clone()) };
+        self._receive_donation(
+            Donation {
+                donor: sender_id.clone(),
+                token: asset,
+                payee_id: donation.payee_id,
+                split_id: donation.split_id.clone(),
+                amount,
+                timestamp: env::block_timestamp(),
+                memo: donation.memo.clone(),
+            },
+            donation.allocation.as_ref(),
+        );
+        if wrapped {
+            self._unwrap(amount);
+        }
+
+        //emit ERC20PaymentReceived(token, _msgSender(), amount);
+        events::emit(
+            "ft_payment_received",
+            json!({
+                "token": token,
+                "from": sender_id,
+                "amount": amount.to_string(),
+                "memo": donation.memo,
+                "allocation": allocation_json(&donation.allocation),
+                "payee_id": donation.payee_id.map(|id| id.to_string()),
+                "split_id": donation.split_id,
+            }),
+        );
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /**
+     * @dev Books a donation from start to end: the record and the donor total, the platform
+     * fee, the payment of the rest to the pool (optionally through an `allocation`), to the
+     * payee of `payee_id` or to the hosted split of `split_id`, the campaign contribution and
+     * the receipt. The storage all of this adds is measured and paid out of an Ether donation,
+     * from the part that went to its destination, so payees never pay for a donor's storage.
+     * The donation is rejected if that part does not cover it. Token donations are limited to
+     * accepted tokens and their minimums instead.
+     */
+    pub(crate) fn _receive_donation(
+        &mut self,
+        donation: Donation,
+        allocation: Option<&Allocation>,
+    ) {
+        let initial_storage: u64 = env::storage_usage();
+        let donor: AccountId = donation.donor.clone();
+        let asset: Option<AccountId> = donation.token.clone();
+        let payee_id: Option<u128> = donation.payee_id;
+        let split_id: Option<String> = donation.split_id.clone();
+        if asset.is_none() {
+            let total_x: Balance = donation.amount + self.donor_total(&donor);
+            self._donor_total.insert(&donor, &total_x);
+        }
+        let net: Balance = self._record_donation(donation);
+        let mut pooled: Balance = net;
+        if let Some(allocation) = allocation {
+            pooled -= self._apply_allocation(asset.as_ref(), allocation, net);
+        }
+        if let Some(payee_id) = &payee_id {
+            let account: AccountId = self._payee_by_id(payee_id);
+            match &asset {
+                Some(token) => self._credit_erc(token, &account, net),
+                None => self._credit(&account, net),
+            }
+        }
+        if let Some(split_id) = &split_id {
+            self._split_receive(split_id, &asset, net);
+        }
+        if asset.is_some() {
+            return;
+        }
+
+        let storage_cost: Balance =
+            (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+        let paid: Balance = if payee_id.is_some() || split_id.is_some() {
+            net
+        } else {
+            pooled
+        };
+        require!(
+            paid > storage_cost,
+            "PaymentSplitter: donation does not cover its storage"
+        );
+        match (&payee_id, &split_id) {
+            (Some(payee_id), _) => {
+                let account: AccountId = self._payee_by_id(payee_id);
+                self._credited
+                    .insert(&account, &(self.credited(&account) - storage_cost));
+                self._total_credited -= storage_cost;
+                self._total_donated -= storage_cost;
+            }
+            (_, Some(split_id)) => self._split_take_back(split_id, &None, storage_cost),
+            _ => {
+                self._total_donated -= storage_cost;
+                if self._campaign_running() {
+                    self._campaign_take_back(&donor, storage_cost);
+                }
+            }
+        }
+    }
+
+    /**
+     * @dev Stores a {Donation} record, books it as a campaign contribution while a campaign is
+     * running and mints a receipt to the donor if the amount reaches the receipt threshold.
+     * Donations to hosted splits do not take part in the campaign.
+     * `memo` is limited to {MAX_MEMO_LENGTH} bytes.
+     * Returns the amount left for the split after the platform fee, which hosted splits pay too.
+     * Only called through {_receive_donation}, which also pays for the storage.
      */
-    // Events are not yet fully supported *****
-    // #[payable]
-    // receive() external payable virtual {
-    //     emit PaymentReceived(_msgSender(), msg.value);
-    // }
+    fn _record_donation(&mut self, donation: Donation) -> Balance {
+        if let Some(text) = &donation.memo {
+            require!(
+                text.len() <= MAX_MEMO_LENGTH,
+                "PaymentSplitter: memo is too long"
+            );
+        }
+        self._donations.push(&donation);
+        let booked: Balance = donation.amount;
+        let net: Balance = self._take_platform_fee(&donation.donor, &donation.token, booked);
+        if donation.split_id.is_none() {
+            self._book_donated(&donation.token, booked);
+        } else {
+            // the fee is booked to the default ledger, which keeps all fees out of the pool
+            self._book_donated(&donation.token, booked - net);
+        }
+        if self._campaign_running() && donation.split_id.is_none() {
+            require!(
+                donation.payee_id.is_none(),
+                "PaymentSplitter: directed gifts are disabled during a campaign"
+            );
+            self._campaign_contribute(&donation.donor, &donation.token, booked, booked - net);
+        }
+        self._mint_receipt(&donation.donor, &donation.token, donation.amount);
+        net
+    }
+
+    /**
+     * @dev Credits the amounts of a donor-directed `allocation` straight to the chosen payees'
+     * entitlements, in Ether if `token` is `None`. Whatever is left of `amount` stays in the
+     * pool and follows the normal `_shares` split. Returns the amount allocated.
+     */
+    fn _apply_allocation(
+        &mut self,
+        token: Option<&AccountId>,
+        allocation: &Allocation,
+        amount: Balance,
+    ) -> Balance {
+        require!(
+            !self._campaign_running(),
+            "PaymentSplitter: directed gifts are disabled during a campaign"
+        );
+        let allocated: Balance = allocation
+            .values()
+            .try_fold(0, |sum: Balance, value| sum.checked_add(*value))
+            .expect("PaymentSplitter: allocation overflow");
+        require!(
+            allocated <= amount,
+            "PaymentSplitter: allocation exceeds donation"
+        );
+        for (id, value) in allocation.iter() {
+            let account: AccountId = self._payee_by_id(id);
+            match token {
+                Some(token) => self._credit_erc(token, &account, *value),
+                None => self._credit(&account, *value),
+            }
+        }
+        allocated
+    }
+
+    /**
+     * @dev Accepts donations of `token` through `ft_on_transfer` from `minimum` on, or stops
+     * accepting it if `minimum` is `None`. Tokens received before stay in the ledger.
+     * Can only be called by the current owner.
+     */
+    pub fn set_accepted_token(&mut self, token: AccountId, minimum: Option<Balance>) {
+        self._only_owner();
+        match minimum {
+            Some(minimum) => {
+                self._accepted_tokens.insert(&token, &minimum);
+            }
+            None => {
+                self._accepted_tokens.remove(&token);
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the minimum donation of an accepted `token`, `None` if it is not accepted.
+     */
+    pub fn accepted_token(&self, token: AccountId) -> Option<Balance> {
+        self._accepted_tokens.get(&token)
+    }
+
+    /**
+     * @dev Resolves a payee ID to its address, panicking on unknown IDs.
+     */
+    fn _payee_by_id(&self, id: &u128) -> AccountId {
+        self._payee_count_to_payee_address
+            .get(id)
+            .expect("PaymentSplitter: unknown payee ID")
+    }
+
+    /**
+     * @dev Adds `amount` of Ether to the entitlement of `account` outside of the `_shares` split,
+     * for donor allocations and single-payee gifts alike.
+     */
+    fn _credit(&mut self, account: &AccountId, amount: Balance) {
+        let credited_x: Balance = amount + self.credited(account);
+        self._credited.insert(account, &credited_x);
+        self._total_credited += amount;
+    }
+
+    /**
+     * @dev Adds `amount` of `token` to the entitlement of `account` outside of the `_shares` split.
+     */
+    fn _credit_erc(&mut self, token: &AccountId, account: &AccountId, amount: Balance) {
+        let mut _erc20_credited_inner: UnorderedMap<AccountId, Balance> =
+            match self._erc20_credited.get(token) {
+                Some(value) => value,
+                None => UnorderedMap::new([b"y".as_slice(), token.as_bytes()].concat()),
+            };
+        let credited_x: Balance = amount + _erc20_credited_inner.get(account).unwrap_or(0);
+        _erc20_credited_inner.insert(account, &credited_x);
+        self._erc20_credited.insert(token, &_erc20_credited_inner);
+
+        let credited_y: Balance = amount + self.total_credited_erc(token);
+        self._erc20_total_credited.insert(token, &credited_y);
+    }
+
+    /**
+     * @dev Getter for the number of donations recorded by {donate}.
+     */
+    pub fn donation_count(&self) -> u64 {
+        self._donations.len()
+    }
+
+    /**
+     * @dev Getter for up to `limit` recorded donations, starting at `from_index`.
+     */
+    pub fn donations(&self, from_index: u64, limit: u64) -> Vec<Donation> {
+        (from_index..std::cmp::min(from_index + limit, self._donations.len()))
+            .map(|index| self._donations.get(index).unwrap())
+            .collect()
+    }
+
+    /**
+     * @dev Getter for the cumulative amount of Ether donated by `donor`, including the storage
+     * and fees paid out of the donations.
+     */
+    pub fn donor_total(&self, donor: &AccountId) -> Balance {
+        self._donor_total.get(donor).unwrap_or(0)
+    }
 
     /**
      * @dev Getter for the total shares held by payees.
@@ -194,7 +882,50 @@ impl PaymentSplitter {
      */
     //START HERE AND RENAME SAME NAME FUNCS/////////*******
     pub fn total_released_erc(&self, token: &AccountId) -> Balance {
-        self._erc20_total_released.get(token).unwrap()
+        self._erc20_total_released.get(token).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the total amount of Ether credited directly to payees by donor allocations
+     * and single-payee gifts, outside of pooled income.
+     */
+    pub fn total_credited(&self) -> Balance {
+        self._total_credited
+    }
+
+    /**
+     * @dev Getter for the total amount of `token` credited directly to payees by donor
+     * allocations and single-payee gifts, outside of pooled income.
+     */
+    pub fn total_credited_erc(&self, token: &AccountId) -> Balance {
+        self._erc20_total_credited.get(token).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the amount of Ether credited directly to a payee by donor allocations and
+     * single-payee gifts.
+     */
+    pub fn credited(&self, account: &AccountId) -> Balance {
+        self._credited.get(account).unwrap_or(0)
+    }
+
+    /**
+     * @dev Getter for the amount of `token` credited directly to a payee by donor allocations
+     * and single-payee gifts.
+     */
+    pub fn credited_erc(&self, token: &AccountId, account: &AccountId) -> Balance {
+        match self._erc20_credited.get(token) {
+            Some(value) => value.get(account).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /**
+     * @dev Getter for the total amount of Ether received into the shared pool, excluding direct
+     * credits and single-payee gifts.
+     */
+    pub fn total_pooled_received(&self) -> Balance {
+        self._total_received()
     }
 
     /**
@@ -208,7 +939,7 @@ impl PaymentSplitter {
      * @dev Getter for the amount of Ether already released to a payee.
      */
     pub fn released(&self, account: &AccountId) -> Balance {
-        self._released.get(account).unwrap()
+        self._released.get(account).unwrap_or(0)
     }
 
     /**
@@ -236,8 +967,33 @@ impl PaymentSplitter {
      * @dev Getter for the amount of payee's releasable Ether.
      */
     pub fn releasable(&self, account: &AccountId) -> u128 {
-        let total_received: Balance = env::account_balance() + self.total_released();
-        self._pending_payment(&account, &total_received, &self.released(&account))
+        self._pending_payment(
+            &None,
+            account,
+            &self._total_received(),
+            &self.credited(account),
+            &self.released(account),
+        )
+    }
+
+    /**
+     * @dev Total Ether received into the shared pool, i.e. everything donated to the default
+     * split minus platform fees and the amounts donors credited or gifted directly to specific
+     * payees. Taken from the donation ledger, so deposits attached to other calls are not
+     * income.
+     */
+    fn _total_received(&self) -> Balance {
+        self._total_donated - self._fees_taken(&None) - self._total_credited
+    }
+
+    /**
+     * @dev Total `token` received into the shared pool, the token counterpart of
+     * {_total_received}, taken from the amounts booked by `ft_on_transfer`.
+     */
+    fn _total_received_erc(&self, token: &AccountId) -> Balance {
+        self.total_donated_erc(token)
+            - self._fees_taken(&Some(token.clone()))
+            - self.total_credited_erc(token)
     }
 
     /**
@@ -245,47 +1001,25 @@ impl PaymentSplitter {
      * should be the address of an IERC20 contract.
      */
     pub fn releasable_erc(&self, token: AccountId, account: &AccountId) -> u128 {
-        let tkn = token.to_string();
-        // let mut total_received: Balance =
-        //     ext_ft::ext(token).ft_balance_of(env::current_account_id().to_string());
-
-        ext_ft::ext(token)
-            .ft_balance_of(env::current_account_id().to_string())
-            .then(Self::ext(env::current_account_id()).query_balance_callback());
-
-        let total_received = self._balance_of + self.total_released_erc(&tkn.parse().unwrap());
         self._pending_payment(
+            &Some(token.clone()),
             account,
-            &total_received,
-            &self.released_erc(&tkn.parse().unwrap(), account),
+            &self._total_received_erc(&token),
+            &self.credited_erc(&token, account),
+            &self.released_erc(&token, account),
         )
     }
-    #[private] // Public - but only callable by env::current_account_id()
-    pub fn query_balance_callback(&mut self) -> Balance {
-        self._balance_of = match env::promise_result(0) {
-            PromiseResult::Successful(value) => {
-                near_sdk::serde_json::from_slice::<Balance>(&value).unwrap()
-                //value
-            }
-            _ => {
-                log!("There was an error contacting NEP-141");
-                return 0;
-            }
-        };
-        self._balance_of
-    }
 
     /**
      * @dev Getter for the amount of payee's full information, including releasable Ether.
      */
     pub fn info(&self, account: AccountId) -> (String, u128, String, String, u128) {
-        let total_received: Balance = env::account_balance() + self.total_released();
         (
             self._payee_name.get(&account).unwrap(),
             self._shares.get(&account).unwrap(),
             self._target_chain.get(&account).unwrap(),
             self._target_wallet.get(&account).unwrap(),
-            self._pending_payment(&account, &total_received, &self.released(&account)),
+            self.releasable(&account),
         )
     }
 
@@ -294,17 +1028,12 @@ impl PaymentSplitter {
      */
     pub fn info_by_id(&self, id: u128) -> (String, u128, String, String, u128) {
         let _recipient_x: AccountId = self._payee_count_to_payee_address.get(&id).unwrap();
-        let total_received: u128 = env::account_balance() + self.total_released();
         (
             self._payee_name.get(&_recipient_x).unwrap(),
             self._shares.get(&_recipient_x).unwrap(),
             self._target_chain.get(&_recipient_x).unwrap(),
             self._target_wallet.get(&_recipient_x).unwrap(),
-            self._pending_payment(
-                &_recipient_x,
-                &total_received,
-                &self.released(&_recipient_x),
-            ),
+            self.releasable(&_recipient_x),
         )
     }
 
@@ -320,15 +1049,47 @@ impl PaymentSplitter {
      * @dev Loop across all recipients, to process payment for them all in one batch.
      * Useful to do before updating "shares" or adding payees, since the release math
      * isn't designed to calculate changing shares.
+     * Payees with nothing due or less than their payout threshold are skipped.
+     * Processes up to `limit` payees (all remaining ones if `None`) starting where the previous
+     * call stopped, so a large roster can be released over several calls. Returns whether the
+     * last payee was reached, in which case the next call starts over at the first.
      */
-    pub fn release_all(&mut self) {
+    pub fn release_all(&mut self, limit: Option<u128>) -> bool {
         require!(
             self._payee_count > 1,
             "PaymentSplitter: only one or zero accounts available"
         );
+        require!(limit != Some(0), "PaymentSplitter: limit is 0");
 
-        for i in 1..self._payee_count {
-            self.release_by_index(&i);
+        let first: u128 = self._release_all_cursor;
+        let last: u128 = match limit {
+            Some(limit) => std::cmp::min(first.saturating_add(limit - 1), self._payee_count),
+            None => self._payee_count,
+        };
+        self._release_range(first, last);
+        let done: bool = last == self._payee_count;
+        self._release_all_cursor = if done { 1 } else { last + 1 };
+        done
+    }
+
+    /**
+     * @dev Getter for the payee ID the next {release_all} call starts at.
+     */
+    pub fn release_all_cursor(&self) -> u128 {
+        self._release_all_cursor
+    }
+
+    /**
+     * @dev Releases the Ether due to the payees with IDs `first` to `last`, skipping those
+     * below their payout threshold.
+     */
+    fn _release_range(&mut self, first: u128, last: u128) {
+        self._settle_caps(&None);
+        for i in first..=last {
+            let _recipient_x: AccountId = self._payee_by_id(&i);
+            if self._meets_threshold(&None, &_recipient_x, self.releasable(&_recipient_x)) {
+                self.release(_recipient_x);
+            }
         }
     }
 
@@ -351,32 +1112,97 @@ impl PaymentSplitter {
     pub fn release(&mut self, account: AccountId) {
         //////////START WORK HERE
         require!(
-            self._shares.get(&account).unwrap() > 0,
+            self._shares.get(&account).unwrap_or(0) > 0,
             "PaymentSplitter: account has no shares"
         );
+        if Self::_msg_sender() == account {
+            self._record_activity(&account);
+        }
 
+        self._settle_caps(&None);
         let payment: Balance = self.releasable(&account);
+        self._release_to(account.clone(), account, payment);
+    }
 
+    /**
+     * @dev Books `payment` as released to `account` and transfers it to `beneficiary`, which is
+     * the payee itself, its backup beneficiary or the owner after a recovery.
+     * The transfer is followed by {on_release_complete}, which rolls the bookkeeping back if the
+     * receiving account no longer exists.
+     */
+    fn _release_to(&mut self, account: AccountId, beneficiary: AccountId, payment: Balance) {
+        self._only_releases_enabled();
         require!(payment != 0, "PaymentSplitter: account is not due payment");
         require!(
             env::account_balance() >= payment,
             "Address: insufficient balance"
         );
+        require!(
+            self._liquid() >= payment,
+            "PaymentSplitter: not enough liquid Ether, unstake first"
+        );
 
         //_released[account] += payment;
-        let payment_x: Balance = payment
-            + match self._released.get(&account) {
-                Some(value) => value,
-                None => 0,
-            };
+        let payment_x: Balance = payment + self.released(&account);
         self._released.insert(&account, &payment_x);
         self._total_released += payment;
 
+        if beneficiary == account && self.is_payee_splitter(&account) {
+            self._release_to_splitter(account, payment);
+            return;
+        }
+        if beneficiary == account && self._pays_wrapped(&account) {
+            self._release_wrapped(account, payment);
+            return;
+        }
         //(bool success, ) = account.call{value: payment}("");
-        Promise::new(account).transfer(payment);
+        Promise::new(beneficiary).transfer(payment).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                .on_release_complete(account, payment),
+        );
         //emit PaymentReleased(account, payment);
     }
 
+    /**
+     * @dev Resolves a NEAR release. If the transfer failed (e.g. the receiving account was
+     * deleted) the refund is back in the contract, so the payment is booked as unreleased again
+     * and the payee is flagged so its backup beneficiary can claim it.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_release_complete(&mut self, account: AccountId, payment: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Release of {} to {} failed, payment restored", payment, account);
+                self._restore_released(&account, payment);
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Books a failed Ether release of `payment` to `account` as unreleased again and flags
+     * the payee so its backup beneficiary can claim it. Splitter payees are not flagged: their
+     * `donate` can revert while the child contract is healthy, which must not make its
+     * entitlement recoverable.
+     */
+    fn _restore_released(&mut self, account: &AccountId, payment: Balance) {
+        self._unrelease(account, payment);
+        if !self.is_payee_splitter(account) {
+            self._release_failed.insert(account, &true);
+        }
+    }
+
+    /**
+     * @dev Books `payment` released to `account` as unreleased again, without flagging the payee.
+     */
+    fn _unrelease(&mut self, account: &AccountId, payment: Balance) {
+        self._released
+            .insert(account, &(self.released(account) - payment));
+        self._total_released -= payment;
+    }
+
     /**
      * @dev Triggers a transfer to `account` of the amount of `token` tokens they are owed, according
      * to their percentage of the total shares and their previous withdrawals. `token` must be the
@@ -384,71 +1210,318 @@ impl PaymentSplitter {
      */
     #[payable]
     pub fn release_erc(&mut self, token: AccountId, account: AccountId) {
-        let tkn: String = token.to_string();
         // let sender_balance: String = ext_ft::ext(tkn).ft_balance_of(_msgSender().to_string())
 
         require!(
-            self._shares.get(&account).unwrap() > 0,
+            self._shares.get(&account).unwrap_or(0) > 0,
             "PaymentSplitter: account has no shares"
         );
+        if Self::_msg_sender() == account {
+            self._record_activity(&account);
+        }
 
-        let payment: Balance = self.releasable_erc(token, &account);
+        self._settle_caps(&Some(token.clone()));
+        let payment: Balance = self.releasable_erc(token.clone(), &account);
+        self._release_erc_to(token, account.clone(), account, payment);
+    }
 
+    /**
+     * @dev Token counterpart of {_release_to}: books `payment` of `token` as released to
+     * `account`, transfers it to `beneficiary` and resolves it in {on_release_erc_complete}.
+     * A payee preferring another token is paid through a swap, see `swap.rs`.
+     */
+    fn _release_erc_to(
+        &mut self,
+        token: AccountId,
+        account: AccountId,
+        beneficiary: AccountId,
+        payment: Balance,
+    ) {
+        self._only_releases_enabled();
         require!(payment != 0, "PaymentSplitter: account is not due payment");
-        require!(
-            env::account_balance() >= payment,
-            "Address: insufficient balance"
-        );
 
-        //Moved prior because its necessary to confirm the
-        //transfer before making a change to contract state
-        //wbtc::ft_transfer({"receiver_id": "bob", "amount": "500000000"})
-        ext_ft::ext(tkn.parse().unwrap()).ft_transfer(
-            account.to_string(),
-            payment.to_string(),
-            None,
-        );
         //_erc20Released[token][account] += payment;/////////
         //_erc20_total_released[token] += payment;/////////////
         // Below code equates to above 2 lines in solidity //
+        self._add_erc_released(&token, &account, payment);
+
+        if beneficiary == account && self.is_payee_splitter(&account) {
+            self._release_erc_to_splitter(token, account, payment);
+            return;
+        }
+        if beneficiary == account {
+            if let Some(route) = self._swap_route(&token, &account) {
+                self._release_erc_swapped(token, account, payment, route);
+                return;
+            }
+        }
+        //wbtc::ft_transfer({"receiver_id": "bob", "amount": "500000000"})
+        ext_ft::ext(token.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(beneficiary.to_string(), payment.to_string(), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                    .on_release_erc_complete(token, account, payment),
+            );
+        /////////////////////////////////////////////////////
+        //emit ERC20PaymentReleased(token, account, payment);
+    }
+
+    /**
+     * @dev Resolves a token release, restoring the payment if `ft_transfer` failed, or the part
+     * a child splitter did not use if it was paid through `ft_transfer_call`.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_release_erc_complete(
+        &mut self,
+        token: AccountId,
+        account: AccountId,
+        payment: Balance,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                // ft_transfer_call returns the amount used by the receiver, ft_transfer nothing
+                if let Ok(used) = near_sdk::serde_json::from_slice::<U128>(&value) {
+                    if used.0 < payment {
+                        self._sub_erc_released(&token, &account, payment - used.0);
+                    }
+                }
+                true
+            }
+            _ => {
+                log!(
+                    "Release of {} {} to {} failed, payment restored",
+                    payment,
+                    token,
+                    account
+                );
+                self._sub_erc_released(&token, &account, payment);
+                if !self.is_payee_splitter(&account) {
+                    self._release_failed.insert(&account, &true);
+                }
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Adds `payment` to the released amount of `token` for `account` and to the token total.
+     */
+    fn _add_erc_released(&mut self, token: &AccountId, account: &AccountId, payment: Balance) {
         let mut _erc20_released_inner: UnorderedMap<AccountId, u128> =
-            match self._erc20_released.get(&tkn.parse().unwrap()) {
+            match self._erc20_released.get(token) {
                 Some(value) => value,
-                None => UnorderedMap::new(b"x"),
+                None => UnorderedMap::new([b"x".as_slice(), token.as_bytes()].concat()),
             };
-        let payment_x: Balance = payment
-            + match _erc20_released_inner.get(&account) {
-                Some(value) => value,
-                None => 0,
-            };
-        _erc20_released_inner.insert(&account, &payment_x);
-        self._erc20_released
-            .insert(&tkn.parse().unwrap(), &_erc20_released_inner);
+        let payment_x: Balance = payment + _erc20_released_inner.get(account).unwrap_or(0);
+        _erc20_released_inner.insert(account, &payment_x);
+        self._erc20_released.insert(token, &_erc20_released_inner);
 
-        let payment_y: Balance = payment
-            + match self._erc20_total_released.get(&tkn.parse().unwrap()) {
-                Some(value) => value,
-                None => 0,
-            };
-        self._erc20_total_released
-            .insert(&tkn.parse().unwrap(), &payment_y);
-        /////////////////////////////////////////////////////
-        //emit ERC20PaymentReleased(token, account, payment);
+        let payment_y: Balance = payment + self.total_released_erc(token);
+        self._erc20_total_released.insert(token, &payment_y);
+    }
+
+    /**
+     * @dev Reverts {_add_erc_released} after a failed transfer.
+     */
+    pub(crate) fn _sub_erc_released(
+        &mut self,
+        token: &AccountId,
+        account: &AccountId,
+        payment: Balance,
+    ) {
+        if let Some(mut _erc20_released_inner) = self._erc20_released.get(token) {
+            let payment_x: Balance = _erc20_released_inner.get(account).unwrap_or(0) - payment;
+            _erc20_released_inner.insert(account, &payment_x);
+            self._erc20_released.insert(token, &_erc20_released_inner);
+        }
+        let payment_y: Balance = self.total_released_erc(token) - payment;
+        self._erc20_total_released.insert(token, &payment_y);
+    }
+
+    /**
+     * @dev Lets a payee name a backup beneficiary and an inactivity period (in nanoseconds).
+     * If a release to the payee fails, or the payee shows no activity for longer than the
+     * period, the backup can claim the payee's accrued entitlement. A period of 0 disables the
+     * inactivity trigger, leaving only failed releases.
+     */
+    pub fn set_backup_beneficiary(&mut self, backup: AccountId, inactivity_period: u64) {
+        let account: AccountId = Self::_msg_sender();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        require!(backup != account, "PaymentSplitter: backup is the payee itself");
+        self._backup_beneficiary.insert(&account, &backup);
+        self._inactivity_period.insert(&account, &inactivity_period);
+        self._record_activity(&account);
+    }
+
+    /**
+     * @dev Proof of life for a payee. Resets the inactivity timer and cancels any pending
+     * recovery requested by the owner.
+     */
+    pub fn ping(&mut self) {
+        let account: AccountId = Self::_msg_sender();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        self._record_activity(&account);
+    }
+
+    /**
+     * @dev Getter for a payee's recovery settings: backup beneficiary, inactivity period,
+     * last activity timestamp, whether the entitlement is currently recoverable and when the
+     * owner requested a recovery (0 if none).
+     */
+    pub fn backup_info(&self, account: AccountId) -> (Option<AccountId>, u64, u64, bool, u64) {
+        (
+            self._backup_beneficiary.get(&account),
+            self._inactivity_period.get(&account).unwrap_or(0),
+            self._last_activity.get(&account).unwrap_or(0),
+            self.is_recoverable(&account),
+            self._recovery_requested_at.get(&account).unwrap_or(0),
+        )
+    }
+
+    /**
+     * @dev A payee's entitlement is recoverable once a release to it has failed, or once it
+     * has been inactive for longer than its inactivity period.
+     */
+    pub fn is_recoverable(&self, account: &AccountId) -> bool {
+        if self._release_failed.get(account).unwrap_or(false) {
+            return true;
+        }
+        let period: u64 = self._inactivity_period.get(account).unwrap_or(0);
+        match self._last_activity.get(account) {
+            Some(last) if period > 0 => env::block_timestamp() > last + period,
+            _ => false,
+        }
+    }
+
+    /**
+     * @dev Releases the Ether entitlement of an unreachable or inactive payee to its backup
+     * beneficiary. Can only be called by the backup beneficiary.
+     */
+    pub fn claim_as_backup(&mut self, account: AccountId) {
+        self._only_backup(&account);
+        self._settle_caps(&None);
+        let payment: Balance = self.releasable(&account);
+        self._release_to(account, Self::_msg_sender(), payment);
+    }
+
+    /**
+     * @dev Releases the `token` entitlement of an unreachable or inactive payee to its backup
+     * beneficiary. Can only be called by the backup beneficiary.
+     */
+    pub fn claim_as_backup_erc(&mut self, token: AccountId, account: AccountId) {
+        self._only_backup(&account);
+        self._settle_caps(&Some(token.clone()));
+        let payment: Balance = self.releasable_erc(token.clone(), &account);
+        self._release_erc_to(token, account, Self::_msg_sender(), payment);
+    }
+
+    /**
+     * @dev First governance step of an owner recovery. Starts a {RECOVERY_DELAY} timelock
+     * during which the payee can cancel the recovery by calling {ping}.
+     */
+    pub fn request_recovery(&mut self, account: AccountId) {
+        self._only_owner();
+        require!(
+            self.is_recoverable(&account),
+            "PaymentSplitter: account is not recoverable"
+        );
+        self._recovery_requested_at
+            .insert(&account, &env::block_timestamp());
+    }
+
+    /**
+     * @dev Releases the Ether entitlement of an unreachable or inactive payee to the owner,
+     * once the recovery requested through {request_recovery} has matured.
+     */
+    pub fn claim_as_owner(&mut self, account: AccountId) {
+        self._only_owner();
+        self._only_matured_recovery(&account);
+        self._settle_caps(&None);
+        let payment: Balance = self.releasable(&account);
+        self._release_to(account, self._owner.clone(), payment);
+    }
+
+    /**
+     * @dev Token counterpart of {claim_as_owner}.
+     */
+    pub fn claim_as_owner_erc(&mut self, token: AccountId, account: AccountId) {
+        self._only_owner();
+        self._only_matured_recovery(&account);
+        self._settle_caps(&Some(token.clone()));
+        let payment: Balance = self.releasable_erc(token.clone(), &account);
+        self._release_erc_to(token, account, self._owner.clone(), payment);
+    }
+
+    /**
+     * @dev Throws if the sender is not the backup beneficiary of a recoverable `account`.
+     */
+    fn _only_backup(&self, account: &AccountId) {
+        require!(
+            self._backup_beneficiary.get(account) == Some(Self::_msg_sender()),
+            "PaymentSplitter: caller is not the backup beneficiary"
+        );
+        require!(
+            self.is_recoverable(account),
+            "PaymentSplitter: account is not recoverable"
+        );
+    }
+
+    /**
+     * @dev Throws if no recovery of `account` was requested or its timelock has not passed yet.
+     */
+    fn _only_matured_recovery(&self, account: &AccountId) {
+        let requested_at: u64 = self
+            ._recovery_requested_at
+            .get(account)
+            .expect("PaymentSplitter: no recovery requested");
+        require!(
+            env::block_timestamp() >= requested_at + RECOVERY_DELAY,
+            "PaymentSplitter: recovery timelock has not passed"
+        );
+        require!(
+            self.is_recoverable(account),
+            "PaymentSplitter: account is not recoverable"
+        );
+    }
+
+    /**
+     * @dev Marks `account` as alive: resets its inactivity timer, clears a failed release
+     * flag and cancels any pending owner recovery.
+     */
+    fn _record_activity(&mut self, account: &AccountId) {
+        self._last_activity
+            .insert(account, &env::block_timestamp());
+        self._release_failed.remove(account);
+        self._recovery_requested_at.remove(account);
     }
 
     /**
      * @dev internal logic for computing the pending payment of an `account`  
-     * given the token historical balances and already released amounts.
+     * given the token historical balances, direct credits and already released amounts.
+     * The pooled part respects the payee caps of `asset`, see {_pooled_entitlement}, and only
+     * the vested part of the entitlement is payable, see {_vested_amount}.
      */
     #[private] // only callable by env::current_account_id()
     fn _pending_payment(
         &self,
+        asset: &Option<AccountId>,
         account: &AccountId,
         total_received: &u128,
+        credited: &u128,
         already_released: &u128,
     ) -> u128 {
-        (total_received * self._shares.get(account).unwrap()) / self._total_shares
-            - already_released
+        let entitlement: u128 =
+            self._pooled_entitlement(asset, account, *total_received) + credited;
+        self._vested_amount(account, entitlement) - already_released
     }
 
     /**
@@ -482,19 +1555,128 @@ impl PaymentSplitter {
         target_chain_: String,
         target_wallet_: AccountId,
     ) {
+        self._only_weighted_shares();
+        self._require_new_payee(&account, shares_);
+
+        if self._payee_count > 1 {
+            self._release_range(1, self._payee_count); // must be done first, because otherwise the addition of a new recipient messes with the release math
+        }
+        self._insert_payee(account, shares_, payee_name_, target_chain_, target_wallet_);
+    }
+
+    /**
+     * @dev Add up to {MAX_PAYEES_PER_CALL} payees in one transaction. The whole batch is
+     * validated before anything is inserted and pending payments are released once up front.
+     * Can only be called by the current owner.
+     */
+    pub fn add_payees(&mut self, payees: Vec<PayeeSpec>) {
+        self._only_owner();
+        self._only_weighted_shares();
+        require!(!payees.is_empty(), "PaymentSplitter: no payees");
+        require!(
+            payees.len() <= MAX_PAYEES_PER_CALL,
+            "PaymentSplitter: too many payees, import in chunks"
+        );
+        for (index, payee) in payees.iter().enumerate() {
+            self._require_new_payee(&payee.account, payee.shares);
+            require!(
+                payees[..index]
+                    .iter()
+                    .all(|other| other.account != payee.account),
+                "PaymentSplitter: duplicate payee in batch"
+            );
+        }
+
+        if self._payee_count > 1 {
+            self._release_range(1, self._payee_count); // must be done first, because otherwise the addition of a new recipient messes with the release math
+        }
+        for payee in payees {
+            if payee.splitter {
+                self._payee_is_splitter.insert(&payee.account, &true);
+            }
+            self._insert_payee(
+                payee.account,
+                payee.shares,
+                payee.name,
+                payee.chain,
+                payee.wallet,
+            );
+        }
+    }
+
+    /**
+     * @dev Getter for up to `limit` payees with their shares and metadata, starting at payee ID
+     * `from_id` (IDs start at 1). Page through it to export the full roster.
+     */
+    pub fn export_payees(&self, from_id: u128, limit: u128) -> Vec<PayeeRecord> {
+        let first: u128 = std::cmp::max(from_id, 1);
+        let last: u128 = std::cmp::min(first.saturating_add(limit), self._payee_count + 1);
+        (first..last)
+            .map(|id| {
+                let account: AccountId = self._payee_by_id(&id);
+                PayeeRecord {
+                    id,
+                    shares: self._shares.get(&account).unwrap(),
+                    name: self._payee_name.get(&account).unwrap(),
+                    chain: self._target_chain.get(&account).unwrap(),
+                    wallet: self._target_wallet.get(&account).unwrap(),
+                    splitter: self.is_payee_splitter(&account),
+                    account,
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * @dev Throws if `account` cannot be added as a payee with `shares_` shares.
+     */
+    fn _require_new_payee(&self, account: &AccountId, shares_: u128) {
         require!(
-            account != "".parse().unwrap(),
+            !account.as_str().is_empty(),
             "PaymentSplitter: account is the zero address"
         );
         require!(shares_ > 0, "PaymentSplitter: shares are 0");
         require!(
-            self._shares.get(&account).unwrap() == 0,
+            self._shares.get(account).unwrap_or(0) == 0,
             "PaymentSplitter: account already has shares"
         );
+    }
 
-        if self._payee_count > 1 {
-            self.release_all(); // must be done first, because otherwise the addition of a new recipient messes with the release math
+    /**
+     * @dev Registers a validated payee. Internal function without access restrictions.
+     */
+    fn _insert_payee(
+        &mut self,
+        account: AccountId,
+        shares_: u128,
+        payee_name_: String,
+        target_chain_: String,
+        target_wallet_: AccountId,
+    ) {
+        self._only_staged_split_idle();
+        // shares change, so everything accrued so far is carried over first: batch releases
+        // skip payees below their threshold, unvested amounts and tokens
+        for asset in self._pool_assets() {
+            if self._cap_allocated.get(&asset).is_some() || self._pool_received(&asset) > 0 {
+                self._only_releases_enabled();
+                self._carry_over(&asset);
+            }
         }
+        self._register_payee(account, shares_, payee_name_, target_chain_, target_wallet_);
+    }
+
+    /**
+     * @dev Adds a validated payee to the roster, once the accrued entitlement has been carried
+     * over. Internal function without access restrictions.
+     */
+    fn _register_payee(
+        &mut self,
+        account: AccountId,
+        shares_: u128,
+        payee_name_: String,
+        target_chain_: String,
+        target_wallet_: AccountId,
+    ) {
         self._payee_count += 1;
         self._payees.push(&account);
         self._shares.insert(&account, &shares_);
@@ -506,82 +1688,105 @@ impl PaymentSplitter {
         self._payees2.push(&self._payee_count);
         self._payee_count_to_payee_address
             .insert(&self._payee_count, &account);
+        self._last_activity
+            .insert(&account, &env::block_timestamp());
         //emit PayeeAdded(account, _payee_count, payee_name_, shares_, target_chain_, target_wallet_);
     }
 }
 
-/*
- * the rest of this file sets up unit tests
- * to run these, the command will be:
- * cargo test --package rust-counter-tutorial -- --nocapture
- * Note: 'rust-counter-tutorial' comes from cargo.toml's 'name' key
- */
-
-// use the attribute below for unit tests
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, VMContext};
-
-    // part of writing unit tests is setting up a mock context
-    // in this example, this is only needed for env::log in the contract
-    // this is also a useful list to peek at when wondering what's available in env::*
-    fn get_context(input: Vec<u8>, is_view: bool) -> VMContext {
-        VMContext {
-            current_account_id: "alice.testnet".to_string(),
-            signer_account_id: "robert.testnet".to_string(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id: "jane.testnet".to_string(),
-            input,
-            block_index: 0,
-            block_timestamp: 0,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 0,
-            attached_deposit: 0,
-            prepaid_gas: 10u64.pow(18),
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-            epoch_height: 19,
-        }
-    }
-
-    // mark individual unit tests with #[test] for them to be registered and fired
+    use crate::testing::*;
+
+    /// Storage cost of what the last call added, given the storage usage before it.
+    fn storage_cost(initial_storage: u64) -> Balance {
+        (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost()
+    }
+
+    #[test]
+    fn donor_pays_the_storage_of_a_donation() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        let initial_storage: u64 = env::storage_usage();
+        contract.donate(None, None);
+        let storage: Balance = storage_cost(initial_storage);
+        // the record, the donor total and the pool booking
+        assert!(storage > 0);
+        assert_eq!(contract.total_donated(), ONE_NEAR - storage);
+        assert_eq!(contract.donor_total(&owner()), ONE_NEAR);
+        assert_eq!(
+            contract.releasable(&bob()) + contract.releasable(&charlie()),
+            ONE_NEAR - storage
+        );
+    }
+
+    #[test]
+    fn gift_pays_its_own_storage() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        let initial_storage: u64 = env::storage_usage();
+        contract.donate_to_payee(1, None);
+        let storage: Balance = storage_cost(initial_storage);
+        assert_eq!(contract.credited(&bob()), ONE_NEAR - storage);
+        assert_eq!(contract.releasable(&bob()), ONE_NEAR - storage);
+        assert_eq!(contract.releasable(&charlie()), 0);
+    }
+
+    #[test]
+    fn allocation_leaves_the_storage_to_the_pool_part() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        let initial_storage: u64 = env::storage_usage();
+        contract.donate(None, Some(HashMap::from([(2, ONE_NEAR / 2)])));
+        let storage: Balance = storage_cost(initial_storage);
+        assert_eq!(contract.credited(&charlie()), ONE_NEAR / 2);
+        assert_eq!(contract.total_donated(), ONE_NEAR - storage);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: donation does not cover its storage")]
+    fn donation_below_its_storage_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(1000));
+        contract.donate(None, None);
+    }
+
+    #[test]
+    fn allocation_is_credited_and_reported() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate(
+            None,
+            Some(HashMap::from([(1, ONE_NEAR / 4), (2, ONE_NEAR / 2)])),
+        );
+        assert_eq!(contract.credited(&bob()), ONE_NEAR / 4);
+        assert_eq!(contract.credited(&charlie()), ONE_NEAR / 2);
+        let log: String = near_sdk::test_utils::get_logs().pop().unwrap();
+        assert!(log.contains(&format!(r#""2":"{}""#, ONE_NEAR / 2)));
+    }
+
     #[test]
-    fn increment() {
-        // set up the mock context into the testing environment
-        let context = get_context(vec![], false);
-        testing_env!(context);
-        // instantiate a contract variable with the counter at zero
-        let mut contract = Counter { val: 0 };
-        contract.increment();
-        println!("Value after increment: {}", contract.get_num());
-        // confirm that we received 1 when calling get_num
-        assert_eq!(1, contract.get_num());
+    #[should_panic(expected = "PaymentSplitter: allocation overflow")]
+    fn overflowing_allocation_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate(None, Some(HashMap::from([(1, 1), (2, u128::MAX)])));
     }
 
     #[test]
-    fn decrement() {
-        let context = get_context(vec![], false);
-        testing_env!(context);
-        let mut contract = Counter { val: 0 };
-        contract.decrement();
-        println!("Value after decrement: {}", contract.get_num());
-        // confirm that we received -1 when calling get_num
-        assert_eq!(-1, contract.get_num());
+    #[should_panic(expected = "PaymentSplitter: allocation exceeds donation")]
+    fn allocation_above_the_donation_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate(None, Some(HashMap::from([(1, ONE_NEAR), (2, 1)])));
     }
 
     #[test]
-    fn increment_and_reset() {
-        let context = get_context(vec![], false);
-        testing_env!(context);
-        let mut contract = Counter { val: 0 };
-        contract.increment();
-        contract.reset();
-        println!("Value after reset: {}", contract.get_num());
-        // confirm that we received 0 when calling get_num
-        assert_eq!(0, contract.get_num());
+    #[should_panic(expected = "PaymentSplitter: donation does not cover its storage")]
+    fn allocation_of_the_whole_donation_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        contract.donate(None, Some(HashMap::from([(2, ONE_NEAR)])));
     }
 }
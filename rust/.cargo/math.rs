@@ -0,0 +1,180 @@
+//! Payout arithmetic with 256-bit intermediates.
+//!
+//! Amounts of 24-decimal NEAR times large share counts do not fit in a u128, so every
+//! `amount * shares / total_shares` goes through {mul_div}, which keeps the full 256-bit product
+//! and only fails if the final result does not fit.
+
+use near_sdk::env;
+
+/**
+ * @dev Returns `a * b / c` rounded down, computed without intermediate overflow.
+ * Throws if `c` is 0 or the result does not fit in a u128.
+ */
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    if c == 0 {
+        env::panic_str("PaymentSplitter: division by zero");
+    }
+    let (hi, lo) = full_mul(a, b);
+    if hi == 0 {
+        return lo / c;
+    }
+    if hi >= c {
+        env::panic_str("PaymentSplitter: arithmetic overflow");
+    }
+    // schoolbook division of the 256-bit product, one bit of `lo` at a time
+    let mut remainder: u128 = hi;
+    let mut quotient: u128 = 0;
+    for bit in (0..128).rev() {
+        let carry: bool = remainder >> 127 == 1;
+        remainder = (remainder << 1) | ((lo >> bit) & 1);
+        quotient <<= 1;
+        if carry || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    quotient
+}
+
+/**
+ * @dev Returns the 256-bit product of `a` and `b` as its high and low halves.
+ */
+fn full_mul(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+
+    let low: u128 = a_lo * b_lo;
+    let cross_1: u128 = a_hi * b_lo;
+    let cross_2: u128 = a_lo * b_hi;
+    let high: u128 = a_hi * b_hi;
+
+    let (lo, carry_1) = low.overflowing_add(cross_1 << 64);
+    let (lo, carry_2) = lo.overflowing_add(cross_2 << 64);
+    let hi: u128 = high + (cross_1 >> 64) + (cross_2 >> 64) + carry_1 as u128 + carry_2 as u128;
+    (hi, lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+    /// Deterministic xorshift generator, so failures can be replayed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn next_u128(&mut self) -> u128 {
+            ((self.next() as u128) << 64) | self.next() as u128
+        }
+
+        /// A value of random bit length, so small and extreme values are both common.
+        fn sized_u128(&mut self) -> u128 {
+            let bits: u64 = self.next() % 129;
+            match bits {
+                0 => 0,
+                128 => self.next_u128(),
+                bits => self.next_u128() >> (128 - bits),
+            }
+        }
+    }
+
+    #[test]
+    fn matches_native_math_when_product_fits() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..10_000 {
+            let (a, b, c) = (rng.sized_u128(), rng.sized_u128(), rng.sized_u128().max(1));
+            if let Some(product) = a.checked_mul(b) {
+                assert_eq!(mul_div(a, b, c), product / c, "{} * {} / {}", a, b, c);
+            }
+        }
+    }
+
+    #[test]
+    fn quotient_is_exact_floor() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..10_000 {
+            let (a, b) = (rng.sized_u128(), rng.sized_u128());
+            let c: u128 = rng.sized_u128().max(1);
+            if full_mul(a, b).0 >= c {
+                continue;
+            }
+            let q: u128 = mul_div(a, b, c);
+            // q * c <= a * b < (q + 1) * c, compared as 256-bit values
+            let product = full_mul(a, b);
+            let (lower_hi, lower_lo) = full_mul(q, c);
+            assert!((lower_hi, lower_lo) <= product, "{} * {} / {}", a, b, c);
+            let (upper_lo, carry) = lower_lo.overflowing_add(c);
+            let upper = (lower_hi + carry as u128, upper_lo);
+            assert!(upper > product, "{} * {} / {}", a, b, c);
+        }
+    }
+
+    #[test]
+    fn full_mul_matches_native_math_and_commutes() {
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        for _ in 0..10_000 {
+            let (a, b) = (rng.sized_u128(), rng.sized_u128());
+            assert_eq!(full_mul(a, b), full_mul(b, a));
+            if let Some(product) = a.checked_mul(b) {
+                assert_eq!(full_mul(a, b), (0, product));
+            }
+        }
+        assert_eq!(full_mul(u128::MAX, u128::MAX), (u128::MAX - 1, 1));
+    }
+
+    #[test]
+    fn payouts_never_exceed_the_pool() {
+        let mut rng = Rng(0x5151_7171_9191_b1b1);
+        for _ in 0..500 {
+            let received: u128 = match rng.next() % 3 {
+                0 => u128::MAX,
+                1 => rng.next_u128() % (ONE_NEAR * 1_000_000_000),
+                _ => rng.sized_u128(),
+            };
+            let payees: usize = 1 + (rng.next() % 20) as usize;
+            let shares: Vec<u128> = (0..payees)
+                .map(|_| 1 + rng.sized_u128() / (payees as u128 + 1))
+                .collect();
+            let total_shares: u128 = shares.iter().sum();
+            let paid: u128 = shares
+                .iter()
+                .map(|shares| mul_div(received, *shares, total_shares))
+                .sum();
+            assert!(paid <= received);
+            assert!(received - paid < payees as u128);
+        }
+    }
+
+    #[test]
+    fn identities_at_the_extremes() {
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(mul_div(u128::MAX, 1, 1), u128::MAX);
+        assert_eq!(mul_div(u128::MAX, u128::MAX - 1, u128::MAX), u128::MAX - 1);
+        assert_eq!(mul_div(0, u128::MAX, 1), 0);
+        assert_eq!(
+            mul_div(ONE_NEAR * 1_000_000_000, u128::MAX / 3, u128::MAX),
+            ONE_NEAR * 1_000_000_000 / 3
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: arithmetic overflow")]
+    fn overflowing_result_panics() {
+        mul_div(u128::MAX, 2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: division by zero")]
+    fn division_by_zero_panics() {
+        mul_div(1, 1, 0);
+    }
+}
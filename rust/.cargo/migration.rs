@@ -0,0 +1,223 @@
+//! Upgrades of deployed splitters.
+//!
+//! The state version is stored under its own key next to the contract state. {new} records the
+//! current version, and {migrate} reads whatever layout the stored version says and rewrites it
+//! in the current one. Splitters deployed before the version was recorded use the original
+//! layout, `PaymentSplitterV0`.
+//!
+//! V0 computed Ether entitlements from the contract balance plus the Ether released, which
+//! counted the storage stake as income. The migration books the liquid Ether plus the Ether
+//! released as donated instead, raised only as far as needed to cover what each payee already
+//! released, as V0 could pay early payees out of the overstated total. V0 kept no token ledger:
+//! each token is booked as donated up to what was released, and the owner books the rest with
+//! {sync_token_balance} and {sweep_surplus}.
+
+use crate::*;
+
+/// Storage key of the state version, outside of any collection prefix.
+const STATE_VERSION_KEY: &[u8] = b"~version";
+/// Version of the current `PaymentSplitter` layout.
+pub const STATE_VERSION: u8 = 1;
+
+/// Layout of the splitters deployed before the state version was recorded.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct PaymentSplitterV0 {
+    _owner: AccountId,
+    _payee_count: u128,
+    _total_shares: Balance,
+    _total_released: Balance,
+
+    // last token balance fetched by the old releasable_erc, replaced by the token ledger
+    _balance_of: Balance,
+
+    _payees2: Vector<u128>,
+    _payees: Vector<AccountId>,
+
+    _shares: UnorderedMap<AccountId, Balance>,
+    _released: UnorderedMap<AccountId, Balance>,
+    _payee_name: UnorderedMap<AccountId, String>,
+    _target_chain: UnorderedMap<AccountId, String>,
+    _target_wallet: UnorderedMap<AccountId, String>,
+    _payee_count_to_payee_address: UnorderedMap<u128, AccountId>,
+
+    _erc20_released: UnorderedMap<AccountId, UnorderedMap<AccountId, Balance>>,
+    _erc20_total_released: UnorderedMap<AccountId, Balance>,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Rewrites the stored state in the current layout after a code upgrade. Throws if the
+     * state is already current. Can only be called by the contract itself, typically in the
+     * same transaction as the deployment.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let this: Self = match state_version() {
+            0 => Self::_migrate_v0(),
+            _ => env::panic_str("PaymentSplitter: state is already at the current version"),
+        };
+        record_state_version();
+        log!(
+            "PaymentSplitter migrated to state version {}",
+            STATE_VERSION
+        );
+        this
+    }
+
+    /**
+     * @dev Getter for the version of the stored state layout.
+     */
+    pub fn state_version(&self) -> u8 {
+        state_version()
+    }
+}
+
+impl PaymentSplitter {
+    fn _migrate_v0() -> Self {
+        let old: PaymentSplitterV0 =
+            env::state_read().expect("PaymentSplitter: no state to migrate");
+        let mut this: Self = Self::_empty(old._owner, SplitterConfig::default());
+        this._payee_count = old._payee_count;
+        this._total_shares = old._total_shares;
+        this._total_released = old._total_released;
+        this._payees2 = old._payees2;
+        this._payees = old._payees;
+        this._shares = old._shares;
+        this._released = old._released;
+        this._payee_name = old._payee_name;
+        this._target_chain = old._target_chain;
+        this._target_wallet = old._target_wallet;
+        this._payee_count_to_payee_address = old._payee_count_to_payee_address;
+        this._erc20_released = old._erc20_released;
+        this._erc20_total_released = old._erc20_total_released;
+
+        this._total_donated = this._liquid() + this._total_released;
+        for account in this._payees.iter() {
+            let covered: Balance = this._total_covering(&account);
+            this._total_donated = std::cmp::max(this._total_donated, covered);
+        }
+        for (token, released) in this._erc20_total_released.iter() {
+            this._erc20_donated.insert(&token, &released);
+        }
+        this
+    }
+
+    /**
+     * @dev Smallest pool total whose share for `account` covers what it has released.
+     */
+    fn _total_covering(&self, account: &AccountId) -> Balance {
+        let shares: Balance = self._shares.get(account).unwrap_or(0);
+        if shares == 0 {
+            return 0;
+        }
+        let released: Balance = self.released(account);
+        let total: Balance = math::mul_div(released, self._total_shares, shares);
+        if math::mul_div(total, shares, self._total_shares) < released {
+            total + 1
+        } else {
+            total
+        }
+    }
+}
+
+/// Version of the stored state, 0 if none was recorded.
+fn state_version() -> u8 {
+    env::storage_read(STATE_VERSION_KEY).map_or(0, |version| version[0])
+}
+
+/// Records that the state is stored in the current layout.
+pub(crate) fn record_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &[STATE_VERSION]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn token() -> AccountId {
+        "token.near".parse().unwrap()
+    }
+
+    /// Stores a V0 splitter with a balance of `balance`, paying {bob} 1 and {charlie} 3 shares,
+    /// that released 1 NEAR and 250 of {token} to {bob}.
+    fn store_v0(balance: Balance) {
+        set_context(context(owner()).account_balance(balance));
+        let mut old = PaymentSplitterV0 {
+            _owner: owner(),
+            _payee_count: 2,
+            _total_shares: 4,
+            _total_released: ONE_NEAR,
+            _balance_of: 0,
+            _payees2: Vector::new(b"b"),
+            _payees: Vector::new(b"a"),
+            _shares: UnorderedMap::new(b"c"),
+            _released: UnorderedMap::new(b"d"),
+            _payee_name: UnorderedMap::new(b"e"),
+            _target_chain: UnorderedMap::new(b"f"),
+            _target_wallet: UnorderedMap::new(b"g"),
+            _payee_count_to_payee_address: UnorderedMap::new(b"h"),
+            _erc20_released: UnorderedMap::new(b"i"),
+            _erc20_total_released: UnorderedMap::new(b"j"),
+        };
+        for (id, (account, shares)) in [(bob(), 1), (charlie(), 3)].into_iter().enumerate() {
+            old._payees.push(&account);
+            old._payees2.push(&shares);
+            old._shares.insert(&account, &shares);
+            old._payee_count_to_payee_address
+                .insert(&(id as u128 + 1), &account);
+        }
+        old._released.insert(&bob(), &ONE_NEAR);
+        let mut released: UnorderedMap<AccountId, Balance> = UnorderedMap::new(b"x");
+        released.insert(&bob(), &250);
+        old._erc20_released.insert(&token(), &released);
+        old._erc20_total_released.insert(&token(), &250);
+        env::state_write(&old);
+    }
+
+    #[test]
+    fn v0_state_keeps_its_payees_and_entitlements() {
+        store_v0(100 * ONE_NEAR);
+        let storage: Balance = env::storage_usage() as Balance * env::storage_byte_cost();
+        let contract: PaymentSplitter = PaymentSplitter::migrate();
+        assert_eq!(contract.state_version(), STATE_VERSION);
+        assert_eq!(contract._owner, owner());
+        assert_eq!(contract.shares(charlie()), 3);
+        assert_eq!(contract.payee(0), bob());
+        // V0 split the balance plus the Ether released, the storage stake is not income
+        let total: Balance = 100 * ONE_NEAR - storage + ONE_NEAR;
+        assert_eq!(contract.total_donated(), total);
+        assert_eq!(contract.releasable(&bob()), total / 4 - ONE_NEAR);
+        assert_eq!(contract.releasable(&charlie()), total * 3 / 4);
+        assert_eq!(contract.released_erc(&token(), &bob()), 250);
+        assert_eq!(contract.total_donated_erc(&token()), 250);
+        assert_eq!(contract.release_all_cursor(), 1);
+    }
+
+    #[test]
+    fn v0_releases_beyond_the_liquid_ether_stay_covered() {
+        // V0 paid bob his share of a total that included the storage stake
+        store_v0(0);
+        let contract: PaymentSplitter = PaymentSplitter::migrate();
+        assert_eq!(contract.total_donated(), 4 * ONE_NEAR);
+        assert_eq!(contract.releasable(&bob()), 0);
+        assert_eq!(contract.releasable(&charlie()), 3 * ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: state is already at the current version")]
+    fn current_state_is_not_migrated_again() {
+        store_v0(100 * ONE_NEAR);
+        env::state_write(&PaymentSplitter::migrate());
+        PaymentSplitter::migrate();
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: state is already at the current version")]
+    fn new_splitter_is_at_the_current_version() {
+        env::state_write(&splitter());
+        PaymentSplitter::migrate();
+    }
+}
@@ -0,0 +1,291 @@
+//! Payees that are splitters themselves.
+//!
+//! A payee registered as a splitter is paid through the child's `donate` (Ether) or through
+//! `ft_transfer_call` (tokens), so the child books the payment as a donation instead of
+//! receiving a bare transfer. A failed payment to a child is restored without flagging the
+//! child as unreachable. {effective_split} walks the tree of splitters to find the share of
+//! every end recipient.
+
+use crate::*;
+
+const GAS_FOR_NESTED_DONATE: Gas = Gas(30_000_000_000_000);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(60_000_000_000_000);
+/// Gas every child gets at least for {effective_split}, on top of an even share of the gas left.
+const GAS_FOR_EFFECTIVE_SPLIT: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_EFFECTIVE_SPLIT_CALLBACK: Gas = Gas(20_000_000_000_000);
+/// Weights returned by {effective_split} are fractions of this unit.
+pub const EFFECTIVE_SPLIT_UNIT: u128 = 1_000_000_000_000_000_000;
+
+#[ext_contract(ext_splitter)]
+#[allow(dead_code)] // only used through the generated `ext_splitter` module
+pub trait ChildSplitter {
+    fn donate(&mut self, memo: Option<String>, allocation: Option<Allocation>);
+    fn effective_split(&mut self, max_depth: u32) -> Vec<(AccountId, U128)>;
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Marks `account` as a splitter contract (or back as a regular account). Releases to a
+     * splitter go through its `donate` / `ft_transfer_call` instead of a bare transfer.
+     * Can only be called by the current owner.
+     */
+    pub fn set_payee_splitter(&mut self, account: AccountId, is_splitter: bool) {
+        self._only_owner();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        if is_splitter {
+            self._payee_is_splitter.insert(&account, &true);
+        } else {
+            self._payee_is_splitter.remove(&account);
+        }
+    }
+
+    /**
+     * @dev Getter for whether `account` is registered as a splitter contract.
+     */
+    pub fn is_payee_splitter(&self, account: &AccountId) -> bool {
+        self._payee_is_splitter.get(account).unwrap_or(false)
+    }
+
+    /**
+     * @dev Computes the effective split across the tree of nested splitters: every end
+     * recipient with its weight as a fraction of {EFFECTIVE_SPLIT_UNIT}. Child splitters are
+     * expanded up to `max_depth` levels deep, deeper ones are reported as recipients.
+     * NEAR views cannot call other contracts, so this has to be called as a transaction.
+     * The gas left is shared among the children so they can expand their own children; the
+     * call fails if any child runs out of gas instead of returning a partial split.
+     */
+    pub fn effective_split(&mut self, max_depth: u32) -> PromiseOrValue<Vec<(AccountId, U128)>> {
+        let children: Vec<AccountId> = self._splitter_payees();
+        if max_depth == 0 || children.is_empty() {
+            return PromiseOrValue::Value(self._local_split(&[], &[]));
+        }
+        let required: Gas =
+            GAS_FOR_EFFECTIVE_SPLIT_CALLBACK + GAS_FOR_EFFECTIVE_SPLIT * children.len() as u64;
+        require!(
+            env::prepaid_gas() - env::used_gas() >= required,
+            "PaymentSplitter: not enough gas to expand the child splitters"
+        );
+        let mut queries: Option<Promise> = None;
+        for child in children {
+            let query: Promise = ext_splitter::ext(child)
+                .with_static_gas(GAS_FOR_EFFECTIVE_SPLIT)
+                .with_unused_gas_weight(1)
+                .effective_split(max_depth - 1);
+            queries = Some(match queries {
+                Some(previous) => previous.and(query),
+                None => query,
+            });
+        }
+        PromiseOrValue::Promise(
+            queries.unwrap().then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_EFFECTIVE_SPLIT_CALLBACK)
+                    .with_unused_gas_weight(0)
+                    .on_effective_split(),
+            ),
+        )
+    }
+
+    /**
+     * @dev Combines the effective splits returned by the child splitters, in the order of
+     * {_splitter_payees}. Throws if a child failed to answer, which usually means it ran out of
+     * gas, so a deep tree never comes back with children wrongly reported as recipients.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_effective_split(&mut self) -> Vec<(AccountId, U128)> {
+        let children: Vec<AccountId> = self._splitter_payees();
+        let mut results: Vec<Vec<(AccountId, U128)>> = vec![];
+        for (index, child) in children.iter().enumerate() {
+            let split: Option<Vec<(AccountId, U128)>> = match env::promise_result(index as u64) {
+                PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value).ok(),
+                _ => None,
+            };
+            match split {
+                Some(split) => results.push(split),
+                None => env::panic_str(&format!(
+                    "PaymentSplitter: child splitter {} did not return its split",
+                    child
+                )),
+            }
+        }
+        self._local_split(&children, &results)
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Payees registered as splitters, in payee ID order.
+     */
+    fn _splitter_payees(&self) -> Vec<AccountId> {
+        (1..=self._payee_count)
+            .map(|id| self._payee_by_id(&id))
+            .filter(|account| self.is_payee_splitter(account))
+            .collect()
+    }
+
+    /**
+     * @dev Weights of this splitter's payees, with each payee in `expanded` replaced by the
+     * matching child split in `results`, scaled by the payee's own weight.
+     */
+    fn _local_split(
+        &self,
+        expanded: &[AccountId],
+        results: &[Vec<(AccountId, U128)>],
+    ) -> Vec<(AccountId, U128)> {
+        let mut weights: Vec<(AccountId, U128)> = vec![];
+        if self._total_shares == 0 {
+            return weights;
+        }
+        for id in 1..=self._payee_count {
+            let account: AccountId = self._payee_by_id(&id);
+            let weight: u128 = math::mul_div(
+                self._shares.get(&account).unwrap(),
+                EFFECTIVE_SPLIT_UNIT,
+                self._total_shares,
+            );
+            match expanded.iter().position(|child| *child == account) {
+                Some(index) => {
+                    for (recipient, child_weight) in results[index].iter() {
+                        weights.push((
+                            recipient.clone(),
+                            U128(math::mul_div(weight, child_weight.0, EFFECTIVE_SPLIT_UNIT)),
+                        ));
+                    }
+                }
+                None => weights.push((account, U128(weight))),
+            }
+        }
+        weights
+    }
+
+    /**
+     * @dev Pays `payment` Ether to the child splitter `account` through its `donate`, resolving
+     * in {on_release_complete} like a regular release.
+     */
+    pub(crate) fn _release_to_splitter(&mut self, account: AccountId, payment: Balance) {
+        ext_splitter::ext(account.clone())
+            .with_attached_deposit(payment)
+            .with_static_gas(GAS_FOR_NESTED_DONATE)
+            .donate(
+                Some(format!("Release from {}", env::current_account_id())),
+                None,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                    .on_release_complete(account, payment),
+            );
+    }
+
+    /**
+     * @dev Pays `payment` of `token` to the child splitter `account` through `ft_transfer_call`,
+     * resolving in {on_release_erc_complete}, which restores whatever the child did not use.
+     */
+    pub(crate) fn _release_erc_to_splitter(
+        &mut self,
+        token: AccountId,
+        account: AccountId,
+        payment: Balance,
+    ) {
+        ext_ft::ext(token.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER_CALL)
+            .ft_transfer_call(
+                account.to_string(),
+                payment.to_string(),
+                None,
+                String::new(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                    .on_release_erc_complete(token, account, payment),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    /// A splitter whose payee {bob} is a splitter itself, holding a donation of 4 NEAR.
+    fn parent() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_payee_splitter(bob(), true);
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        contract
+    }
+
+    #[test]
+    fn failed_payment_to_a_child_is_restored_without_flag() {
+        let mut contract: PaymentSplitter = parent();
+        set_context(&context(bob()));
+        let payment: Balance = contract.releasable(&bob());
+        contract.release(bob());
+        let donate: Call = call("donate");
+        assert_eq!((donate.receiver, donate.deposit), (bob(), payment));
+
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_complete(bob(), payment));
+        assert_eq!(contract.releasable(&bob()), payment);
+        assert!(!contract.is_recoverable(&bob()));
+    }
+
+    #[test]
+    fn failed_payment_to_a_regular_payee_flags_it() {
+        let mut contract: PaymentSplitter = parent();
+        set_context(&context(charlie()));
+        let payment: Balance = contract.releasable(&charlie());
+        contract.release(charlie());
+
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_complete(charlie(), payment));
+        assert!(contract.is_recoverable(&charlie()));
+    }
+
+    #[test]
+    fn effective_split_expands_children() {
+        let mut contract: PaymentSplitter = parent();
+        contract.effective_split(2);
+        let query: Call = call("effective_split");
+        assert_eq!(query.receiver, bob());
+        assert_eq!(query.args["max_depth"], json!(1));
+
+        let child_split: Vec<(AccountId, U128)> = vec![
+            (accounts(3), U128(EFFECTIVE_SPLIT_UNIT / 2)),
+            (accounts(4), U128(EFFECTIVE_SPLIT_UNIT / 2)),
+        ];
+        set_callback_context(&context(splitter_account()), json_result(&child_split));
+        assert_eq!(
+            contract.on_effective_split(),
+            vec![
+                (accounts(3), U128(EFFECTIVE_SPLIT_UNIT / 8)),
+                (accounts(4), U128(EFFECTIVE_SPLIT_UNIT / 8)),
+                (charlie(), U128(EFFECTIVE_SPLIT_UNIT * 3 / 4)),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: child splitter bob did not return its split")]
+    fn child_without_answer_fails_the_split() {
+        let mut contract: PaymentSplitter = parent();
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        contract.on_effective_split();
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: not enough gas to expand the child splitters")]
+    fn effective_split_needs_gas_for_the_children() {
+        let mut contract: PaymentSplitter = parent();
+        set_context(context(owner()).prepaid_gas(GAS_FOR_EFFECTIVE_SPLIT_CALLBACK));
+        contract.effective_split(2);
+    }
+}
@@ -0,0 +1,20 @@
+//! The payee type {new} takes. The factory deploying splitters includes this file as is, so it
+//! only depends on near-sdk.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{AccountId, Balance};
+
+/// A payee as passed to {new}: the same fields {add_payee} takes, plus whether the payee is a
+/// splitter contract itself (see {set_payee_splitter}).
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayeeSpec {
+    pub account: AccountId,
+    pub shares: Balance,
+    pub name: String,
+    pub chain: String,
+    pub wallet: AccountId,
+    #[serde(default)]
+    pub splitter: bool,
+}
@@ -0,0 +1,334 @@
+//! Donation receipts as soulbound NEP-171 tokens.
+//!
+//! Every donation at or above the configured threshold for its asset mints a receipt to the
+//! donor. Receipts carry the donated amount, asset, timestamp and a snapshot of the splitter
+//! state in their metadata, and cannot be transferred. The splitter implements the core,
+//! metadata (NEP-177) and enumeration (NEP-181) views itself.
+//! A receipt is stored as part of its donation, so an Ether donation pays for its receipt like
+//! for the rest of its storage. Token receipts are bounded by the token's receipt threshold.
+
+use crate::*;
+
+pub const NFT_METADATA_SPEC: &str = "nft-1.0.0";
+/// Version of the NEP-171 event standard the `nft_mint` events follow.
+pub const NFT_EVENT_VERSION: &str = "1.0.0";
+
+/// NEP-177 contract level metadata, returned by {nft_metadata}.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+/// NEP-177 token metadata. `extra` holds the receipt details as JSON.
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<String>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+/// A minted receipt as stored by the splitter.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Receipt {
+    pub owner_id: AccountId,
+    pub metadata: TokenMetadata,
+}
+
+/// NEP-171 token view. Receipts are soulbound, so `approved_account_ids` is always empty.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Token {
+    pub token_id: String,
+    pub owner_id: AccountId,
+    pub metadata: Option<TokenMetadata>,
+    pub approved_account_ids: Option<HashMap<AccountId, u64>>,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the minimum donation that earns a receipt, for Ether if `token` is `None` or for
+     * the given NEP-141 `token`. A `threshold` of `None` stops minting receipts for that asset.
+     * Can only be called by the current owner.
+     */
+    pub fn set_receipt_threshold(&mut self, token: Option<AccountId>, threshold: Option<Balance>) {
+        self._only_owner();
+        match (token, threshold) {
+            (None, threshold) => self._receipt_threshold = threshold,
+            (Some(token), Some(threshold)) => {
+                self._erc20_receipt_threshold.insert(&token, &threshold);
+            }
+            (Some(token), None) => {
+                self._erc20_receipt_threshold.remove(&token);
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the receipt threshold of Ether (`token` is `None`) or of a NEP-141 `token`.
+     */
+    pub fn receipt_threshold(&self, token: Option<AccountId>) -> Option<Balance> {
+        match token {
+            None => self._receipt_threshold,
+            Some(token) => self._erc20_receipt_threshold.get(&token),
+        }
+    }
+
+    /**
+     * @dev Receipts are soulbound and cannot be transferred.
+     */
+    #[payable]
+    pub fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: String,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        let _ = (receiver_id, token_id, approval_id, memo);
+        env::panic_str("PaymentSplitter: receipts are non-transferable");
+    }
+
+    /**
+     * @dev Receipts are soulbound and cannot be transferred.
+     */
+    #[payable]
+    pub fn nft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: String,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let _ = (receiver_id, token_id, approval_id, memo, msg);
+        env::panic_str("PaymentSplitter: receipts are non-transferable");
+    }
+
+    /**
+     * @dev Getter for a single receipt token.
+     */
+    pub fn nft_token(&self, token_id: String) -> Option<Token> {
+        self._receipts
+            .get(&token_id)
+            .map(|receipt| Self::_receipt_to_token(token_id, receipt))
+    }
+
+    /**
+     * @dev Getter for the contract level NEP-177 metadata.
+     */
+    pub fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.to_string(),
+            name: "PaymentSplitter donation receipts".to_string(),
+            symbol: "RECEIPT".to_string(),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+
+    /**
+     * @dev Getter for the number of receipts minted so far.
+     */
+    pub fn nft_total_supply(&self) -> U128 {
+        U128(self._receipts.len() as u128)
+    }
+
+    /**
+     * @dev Getter for up to `limit` receipts, starting at `from_index`.
+     */
+    pub fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Token> {
+        let start: usize = from_index.map(|index| index.0 as usize).unwrap_or(0);
+        self._receipts
+            .iter()
+            .skip(start)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|(token_id, receipt)| Self::_receipt_to_token(token_id, receipt))
+            .collect()
+    }
+
+    /**
+     * @dev Getter for the number of receipts held by `account_id`.
+     */
+    pub fn nft_supply_for_owner(&self, account_id: AccountId) -> U128 {
+        match self._receipts_per_owner.get(&account_id) {
+            Some(token_ids) => U128(token_ids.len() as u128),
+            None => U128(0),
+        }
+    }
+
+    /**
+     * @dev Getter for up to `limit` receipts held by `account_id`, starting at `from_index`.
+     */
+    pub fn nft_tokens_for_owner(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<Token> {
+        let token_ids: UnorderedSet<String> = match self._receipts_per_owner.get(&account_id) {
+            Some(token_ids) => token_ids,
+            None => return vec![],
+        };
+        let start: usize = from_index.map(|index| index.0 as usize).unwrap_or(0);
+        token_ids
+            .iter()
+            .skip(start)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|token_id| {
+                let receipt: Receipt = self._receipts.get(&token_id).unwrap();
+                Self::_receipt_to_token(token_id, receipt)
+            })
+            .collect()
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Mints a receipt to `donor` if `amount` reaches the threshold configured for the
+     * asset. The metadata snapshots the splitter state right after the donation was booked.
+     */
+    pub(crate) fn _mint_receipt(
+        &mut self,
+        donor: &AccountId,
+        token: &Option<AccountId>,
+        amount: Balance,
+    ) {
+        let threshold: Option<Balance> = self.receipt_threshold(token.clone());
+        match threshold {
+            Some(threshold) if amount >= threshold => {}
+            _ => return,
+        }
+
+        let token_id: String = (self._receipts.len() + 1).to_string();
+        let asset: String = match token {
+            Some(token) => token.to_string(),
+            None => "NEAR".to_string(),
+        };
+        let total_released: Balance = match token {
+            Some(token) => self.total_released_erc(token),
+            None => self.total_released(),
+        };
+        let extra = json!({
+            "amount": amount.to_string(),
+            "asset": asset,
+            "timestamp": env::block_timestamp().to_string(),
+            "snapshot": {
+                "payee_count": self._payee_count.to_string(),
+                "total_shares": self._total_shares.to_string(),
+                "total_released": total_released.to_string(),
+                "donation_count": self._donations.len().to_string(),
+            },
+        });
+        let metadata = TokenMetadata {
+            title: Some(format!("Donation receipt #{}", token_id)),
+            description: Some(format!(
+                "Donation of {} {} to {}",
+                amount,
+                asset,
+                env::current_account_id()
+            )),
+            media: None,
+            media_hash: None,
+            copies: Some(1),
+            issued_at: Some((env::block_timestamp() / 1_000_000).to_string()),
+            expires_at: None,
+            starts_at: None,
+            updated_at: None,
+            extra: Some(extra.to_string()),
+            reference: None,
+            reference_hash: None,
+        };
+        self._receipts.insert(
+            &token_id,
+            &Receipt {
+                owner_id: donor.clone(),
+                metadata,
+            },
+        );
+
+        let mut token_ids: UnorderedSet<String> = match self._receipts_per_owner.get(donor) {
+            Some(value) => value,
+            None => UnorderedSet::new([b"D".as_slice(), donor.as_bytes()].concat()),
+        };
+        token_ids.insert(&token_id);
+        self._receipts_per_owner.insert(donor, &token_ids);
+
+        events::emit_with_standard(
+            "nep171",
+            NFT_EVENT_VERSION,
+            "nft_mint",
+            json!({
+                "owner_id": donor,
+                "token_ids": [token_id],
+            }),
+        );
+    }
+
+    fn _receipt_to_token(token_id: String, receipt: Receipt) -> Token {
+        Token {
+            token_id,
+            owner_id: receipt.owner_id,
+            metadata: Some(receipt.metadata),
+            approved_account_ids: Some(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Donates 1 NEAR from {owner} and returns the storage it added and the amount booked.
+    fn donate(contract: &mut PaymentSplitter) -> (Balance, Balance) {
+        set_context(context(owner()).attached_deposit(ONE_NEAR));
+        let initial_storage: u64 = env::storage_usage();
+        let donated: Balance = contract.total_donated();
+        contract.donate(None, None);
+        let storage: Balance =
+            (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+        (storage, contract.total_donated() - donated)
+    }
+
+    #[test]
+    fn donor_pays_for_the_receipt() {
+        let mut contract: PaymentSplitter = splitter();
+        donate(&mut contract);
+        let (plain, _) = donate(&mut contract);
+
+        set_context(&context(owner()));
+        contract.set_receipt_threshold(None, Some(ONE_NEAR));
+        let (with_receipt, booked) = donate(&mut contract);
+        assert_eq!(contract.nft_supply_for_owner(owner()), U128(1));
+        assert!(with_receipt > plain);
+        assert_eq!(booked, ONE_NEAR - with_receipt);
+    }
+
+    #[test]
+    fn donation_below_the_threshold_mints_nothing() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_receipt_threshold(None, Some(2 * ONE_NEAR));
+        donate(&mut contract);
+        assert_eq!(contract.nft_total_supply(), U128(0));
+    }
+}
@@ -0,0 +1,286 @@
+//! Scheduled release rounds.
+//!
+//! Once a {ReleaseSchedule} is set, anyone can trigger a release round of Ether to all payees
+//! every `interval`, as long as at least `min_amount` is due in total. The caller is paid
+//! `keeper_tip` out of the round, taken from every payment in proportion to its size. Payees
+//! below their payout threshold are left out of the round. Large rosters are processed over
+//! several calls, tracked by a stored {ReleaseRound}.
+
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReleaseSchedule {
+    /// Minimum time between two rounds, in nanoseconds.
+    pub interval: u64,
+    /// Minimum total Ether a round has to release.
+    pub min_amount: Balance,
+    /// Ether paid to the caller of a round.
+    pub keeper_tip: Balance,
+}
+
+/// Progress of a release round processed over several calls of {release_round}.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReleaseRound {
+    /// Payee ID the next call starts at.
+    pub next_id: u128,
+    /// Ether due to all payees, tallied in the first pass.
+    pub due: Balance,
+    /// Whether the tally is complete and the payees are being paid.
+    pub paying: bool,
+    /// Ether released so far, keeper tips included.
+    pub released: Balance,
+    /// Keeper tips paid so far.
+    pub tip: Balance,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the release schedule, or removes it if `schedule` is `None`. The first round is
+     * due one `interval` from now, and a round in progress is dropped.
+     * Can only be called by the current owner.
+     */
+    pub fn set_release_schedule(&mut self, schedule: Option<ReleaseSchedule>) {
+        self._only_owner();
+        if let Some(schedule) = &schedule {
+            require!(schedule.interval > 0, "PaymentSplitter: interval is 0");
+            require!(
+                schedule.keeper_tip <= schedule.min_amount,
+                "PaymentSplitter: keeper tip exceeds the minimum amount"
+            );
+        }
+        self._release_schedule = schedule;
+        self._last_release_round = env::block_timestamp();
+        self._release_round = None;
+    }
+
+    /**
+     * @dev Getter for the release schedule, if any.
+     */
+    pub fn release_schedule(&self) -> Option<ReleaseSchedule> {
+        self._release_schedule.clone()
+    }
+
+    /**
+     * @dev Getter for the block timestamp from which the next release round may be triggered,
+     * `None` if there is no schedule. The round also needs the minimum amount to be due.
+     */
+    pub fn next_release_time(&self) -> Option<u64> {
+        self._release_schedule
+            .as_ref()
+            .map(|schedule| self._last_release_round + schedule.interval)
+    }
+
+    /**
+     * @dev Getter for the progress of the release round in progress, if any.
+     */
+    pub fn release_round_progress(&self) -> Option<ReleaseRound> {
+        self._release_round.clone()
+    }
+
+    /**
+     * @dev Releases the Ether due to every payee and pays the keeper tip out of the released
+     * amounts. Payees with nothing due or less than their payout threshold are skipped.
+     * A round is processed in calls of up to `limit` payees (all remaining ones if `None`):
+     * the first pass tallies what is due, and if that reaches the minimum amount the second
+     * pass pays it out, each caller getting the tip share of the payees it paid. If less than
+     * the minimum amount is due, the round is cancelled once the tally is complete.
+     * Returns whether the round is over.
+     * Throws before {next_release_time} when no round is in progress.
+     */
+    pub fn release_round(&mut self, limit: Option<u128>) -> bool {
+        let schedule: ReleaseSchedule = self
+            ._release_schedule
+            .clone()
+            .expect("PaymentSplitter: no release schedule");
+        require!(limit != Some(0), "PaymentSplitter: limit is 0");
+        self._only_releases_enabled();
+        let started: bool = self._release_round.is_none();
+        let mut round: ReleaseRound = match self._release_round.clone() {
+            Some(round) => round,
+            None => {
+                require!(
+                    env::block_timestamp() >= self._last_release_round + schedule.interval,
+                    "PaymentSplitter: release round is not due yet"
+                );
+                ReleaseRound {
+                    next_id: 1,
+                    due: 0,
+                    paying: false,
+                    released: 0,
+                    tip: 0,
+                }
+            }
+        };
+        self._settle_caps(&None);
+
+        let keeper: AccountId = Self::_msg_sender();
+        let mut budget: u128 = limit.unwrap_or(u128::MAX);
+        let mut tip: Balance = 0;
+        let mut done: bool = false;
+        while budget > 0 && !done {
+            let first: u128 = round.next_id;
+            let last: u128 = std::cmp::min(first.saturating_add(budget - 1), self._payee_count);
+            budget -= last + 1 - first;
+            for id in first..=last {
+                let account: AccountId = self._payee_by_id(&id);
+                let payment: Balance = self.releasable(&account);
+                if !self._meets_threshold(&None, &account, payment) {
+                    continue;
+                }
+                if !round.paying {
+                    round.due += payment;
+                    continue;
+                }
+                // payments can have grown since the tally, the tips never exceed the keeper tip
+                let tip_share: Balance = std::cmp::min(
+                    math::mul_div(schedule.keeper_tip, payment, round.due),
+                    schedule.keeper_tip - round.tip - tip,
+                );
+                // the tip share is paid out of the payee's entitlement, so it is booked as released
+                let released_x: Balance = tip_share + self.released(&account);
+                self._released.insert(&account, &released_x);
+                self._total_released += tip_share;
+                tip += tip_share;
+                round.released += payment;
+                if payment > tip_share {
+                    self._release_to(account.clone(), account, payment - tip_share);
+                }
+            }
+            round.next_id = last + 1;
+            if last < self._payee_count {
+                break;
+            }
+            if round.paying {
+                done = true;
+            } else if round.due == 0 || round.due < schedule.min_amount {
+                // a round tallied in a single call throws, so nothing is stored
+                require!(
+                    !started,
+                    "PaymentSplitter: less than the minimum amount is due"
+                );
+                log!("Less than the minimum amount is due, release round cancelled");
+                self._release_round = None;
+                return true;
+            } else {
+                self._last_release_round = env::block_timestamp();
+                round.next_id = 1;
+                round.paying = true;
+            }
+        }
+
+        round.tip += tip;
+        if tip > 0 {
+            Promise::new(keeper.clone()).transfer(tip);
+        }
+        if !done {
+            self._release_round = Some(round);
+            return false;
+        }
+        self._release_round = None;
+        events::emit(
+            "release_round",
+            json!({
+                "keeper": keeper,
+                "released": round.released.to_string(),
+                "keeper_tip": round.tip.to_string(),
+            }),
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    const INTERVAL: u64 = 100;
+    const TIP: Balance = ONE_NEAR / 10;
+
+    fn keeper() -> AccountId {
+        accounts(3)
+    }
+
+    /// A splitter with a release schedule and a donation of 4 NEAR, at the time the first round
+    /// is due.
+    fn scheduled(min_amount: Balance) -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_release_schedule(Some(ReleaseSchedule {
+            interval: INTERVAL,
+            min_amount,
+            keeper_tip: TIP,
+        }));
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        as_keeper();
+        contract
+    }
+
+    /// Switches to a call by {keeper} once the first round is due, with no receipts yet.
+    fn as_keeper() {
+        set_context(context(keeper()).block_timestamp(INTERVAL));
+    }
+
+    #[test]
+    fn round_tallies_then_pays_over_several_calls() {
+        let mut contract: PaymentSplitter = scheduled(ONE_NEAR);
+        let due_bob: Balance = contract.releasable(&bob());
+        let due_charlie: Balance = contract.releasable(&charlie());
+        let due: Balance = due_bob + due_charlie;
+
+        // tally bob, then charlie
+        assert!(!contract.release_round(Some(1)));
+        let round: ReleaseRound = contract.release_round_progress().unwrap();
+        assert_eq!(
+            (round.next_id, round.due, round.paying),
+            (2, due_bob, false)
+        );
+        assert!(!contract.release_round(Some(1)));
+        let round: ReleaseRound = contract.release_round_progress().unwrap();
+        assert_eq!((round.next_id, round.due, round.paying), (1, due, true));
+        assert!(transfers().is_empty());
+        assert_eq!(contract.next_release_time(), Some(2 * INTERVAL));
+
+        // pay bob, then charlie, each call tipping the keeper for the payees it paid
+        let tip_bob: Balance = math::mul_div(TIP, due_bob, due);
+        assert!(!contract.release_round(Some(1)));
+        assert_eq!(
+            transfers(),
+            vec![(bob(), due_bob - tip_bob), (keeper(), tip_bob)]
+        );
+        as_keeper();
+        assert!(contract.release_round(Some(1)));
+        assert_eq!(
+            transfers(),
+            vec![
+                (charlie(), due_charlie - (TIP - tip_bob)),
+                (keeper(), TIP - tip_bob)
+            ]
+        );
+        assert!(contract.release_round_progress().is_none());
+        assert_eq!(contract.released(&bob()), due_bob);
+        assert_eq!(contract.released(&charlie()), due_charlie);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: release round is not due yet")]
+    fn round_before_the_interval_is_rejected() {
+        let mut contract: PaymentSplitter = scheduled(ONE_NEAR);
+        set_context(context(keeper()).block_timestamp(INTERVAL - 1));
+        contract.release_round(None);
+    }
+
+    #[test]
+    fn round_below_the_minimum_is_cancelled_after_the_tally() {
+        let mut contract: PaymentSplitter = scheduled(5 * ONE_NEAR);
+        assert!(!contract.release_round(Some(1)));
+        assert!(contract.release_round(Some(1)));
+        assert!(contract.release_round_progress().is_none());
+        assert!(transfers().is_empty());
+        assert_eq!(contract.released(&bob()), 0);
+    }
+}
@@ -0,0 +1,533 @@
+//! Independent named splits hosted by a single deployment.
+//!
+//! Besides its own default split, the contract can host any number of splits keyed by a split
+//! ID, each with its own admins, payees, shares and per-asset ledgers. Donations and releases
+//! are addressed to a split ID, and the funds held for splits are kept out of the default pool.
+//!
+//! Unlike the default split, a split keeps an explicit ledger of what it received, so shares
+//! can change at any time: on every change the entitlement accrued so far is carried over per
+//! payee and only income received afterwards follows the new shares.
+//!
+//! Split admins pay for the storage their changes add with the attached deposit; the rest of
+//! the deposit is refunded.
+
+use crate::*;
+
+const MAX_SPLIT_ID_LENGTH: usize = 64;
+
+/// An asset held by a split: `None` for Ether (NEAR), or a NEP-141 token contract.
+pub type Asset = Option<AccountId>;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Split {
+    pub admins: UnorderedSet<AccountId>,
+    pub payees: Vector<AccountId>,
+    pub shares: UnorderedMap<AccountId, Balance>,
+    pub total_shares: Balance,
+    /// Total received per asset.
+    pub received: UnorderedMap<Asset, Balance>,
+    /// Part of `received` already carried over into `carried` at the last share change.
+    pub allocated: UnorderedMap<Asset, Balance>,
+    pub carried: UnorderedMap<(Asset, AccountId), Balance>,
+    pub released: UnorderedMap<(Asset, AccountId), Balance>,
+    pub total_released: UnorderedMap<Asset, Balance>,
+}
+
+impl Split {
+    fn new(split_id: &str) -> Self {
+        Self {
+            admins: UnorderedSet::new(split_prefix(split_id, b'a')),
+            payees: Vector::new(split_prefix(split_id, b'b')),
+            shares: UnorderedMap::new(split_prefix(split_id, b'c')),
+            total_shares: 0,
+            received: UnorderedMap::new(split_prefix(split_id, b'd')),
+            allocated: UnorderedMap::new(split_prefix(split_id, b'e')),
+            carried: UnorderedMap::new(split_prefix(split_id, b'f')),
+            released: UnorderedMap::new(split_prefix(split_id, b'g')),
+            total_released: UnorderedMap::new(split_prefix(split_id, b'h')),
+        }
+    }
+
+    /**
+     * @dev Amount of `asset` `account` may still release from this split.
+     */
+    pub fn releasable(&self, asset: &Asset, account: &AccountId) -> Balance {
+        let shares: Balance = self.shares.get(account).unwrap_or(0);
+        let key = (asset.clone(), account.clone());
+        let unallocated: Balance =
+            self.received.get(asset).unwrap_or(0) - self.allocated.get(asset).unwrap_or(0);
+        let accrued: Balance = match self.total_shares {
+            0 => 0,
+            total_shares => math::mul_div(unallocated, shares, total_shares),
+        };
+        accrued + self.carried.get(&key).unwrap_or(0) - self.released.get(&key).unwrap_or(0)
+    }
+
+    /**
+     * @dev Carries every payee's accrued entitlement over before shares change, for every asset
+     * the split has received. Rounding dust stays unallocated and follows the new shares.
+     */
+    fn carry_over(&mut self) {
+        if self.total_shares == 0 {
+            return;
+        }
+        for (asset, received) in self.received.iter() {
+            let unallocated: Balance = received - self.allocated.get(&asset).unwrap_or(0);
+            let mut allocated: Balance = 0;
+            for account in self.payees.iter() {
+                let accrued: Balance = math::mul_div(
+                    unallocated,
+                    self.shares.get(&account).unwrap(),
+                    self.total_shares,
+                );
+                let key = (asset.clone(), account);
+                let carried_x: Balance = accrued + self.carried.get(&key).unwrap_or(0);
+                self.carried.insert(&key, &carried_x);
+                allocated += accrued;
+            }
+            let allocated_x: Balance = allocated + self.allocated.get(&asset).unwrap_or(0);
+            self.allocated.insert(&asset, &allocated_x);
+        }
+    }
+}
+
+/// Storage prefix of a collection `tag` belonging to split `split_id`.
+fn split_prefix(split_id: &str, tag: u8) -> Vec<u8> {
+    [b"K".as_slice(), split_id.as_bytes(), &[b'/', tag]].concat()
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Creates a new, empty split named `split_id` managed by `admins`. Split IDs are 1 to
+     * {MAX_SPLIT_ID_LENGTH} characters out of `a-z`, `0-9`, `_` and `-`, so no ID can run into
+     * the storage prefix of another split. Can only be called by the current owner.
+     */
+    pub fn create_split(&mut self, split_id: String, admins: Vec<AccountId>) {
+        self._only_owner();
+        require!(
+            !split_id.is_empty()
+                && split_id.len() <= MAX_SPLIT_ID_LENGTH
+                && split_id.bytes().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_' || c == b'-'
+                }),
+            "PaymentSplitter: invalid split ID"
+        );
+        require!(
+            self._splits.get(&split_id).is_none(),
+            "PaymentSplitter: split already exists"
+        );
+        require!(!admins.is_empty(), "PaymentSplitter: split has no admins");
+        let mut split: Split = Split::new(&split_id);
+        for admin in admins.iter() {
+            split.admins.insert(admin);
+        }
+        self._splits.insert(&split_id, &split);
+        events::emit(
+            "split_created",
+            json!({
+                "split_id": split_id,
+                "admins": admins,
+            }),
+        );
+    }
+
+    /**
+     * @dev Adds `admin` to the admins of `split_id`, paying its storage with the attached
+     * deposit. Can only be called by an admin of the split.
+     */
+    #[payable]
+    pub fn split_add_admin(&mut self, split_id: String, admin: AccountId) {
+        let initial_storage: u64 = env::storage_usage();
+        let mut split: Split = self._only_split_admin(&split_id);
+        split.admins.insert(&admin);
+        self._splits.insert(&split_id, &split);
+        self._charge_split_storage(initial_storage);
+    }
+
+    /**
+     * @dev Removes `admin` from the admins of `split_id`. The last admin cannot be removed.
+     * Can only be called by an admin of the split.
+     */
+    pub fn split_remove_admin(&mut self, split_id: String, admin: AccountId) {
+        let mut split: Split = self._only_split_admin(&split_id);
+        split.admins.remove(&admin);
+        require!(
+            !split.admins.is_empty(),
+            "PaymentSplitter: split has no admins"
+        );
+        self._splits.insert(&split_id, &split);
+    }
+
+    /**
+     * @dev Adds a payee to `split_id`, or changes the shares of an existing one. A payee is
+     * removed by setting its shares to 0; its accrued entitlement stays releasable.
+     * The storage the change adds is paid with the attached deposit.
+     * Can only be called by an admin of the split.
+     */
+    #[payable]
+    pub fn split_set_payee(&mut self, split_id: String, account: AccountId, shares_: Balance) {
+        let initial_storage: u64 = env::storage_usage();
+        let mut split: Split = self._only_split_admin(&split_id);
+        split.carry_over();
+
+        let previous: Option<Balance> = split.shares.get(&account);
+        if previous.is_none() {
+            require!(shares_ > 0, "PaymentSplitter: shares are 0");
+            split.payees.push(&account);
+        }
+        split.total_shares = split.total_shares - previous.unwrap_or(0) + shares_;
+        split.shares.insert(&account, &shares_);
+        self._splits.insert(&split_id, &split);
+        events::emit(
+            "split_payee_set",
+            json!({
+                "split_id": split_id,
+                "account": account,
+                "shares": shares_.to_string(),
+            }),
+        );
+        self._charge_split_storage(initial_storage);
+    }
+
+    /**
+     * @dev Donates the attached Ether to `split_id`.
+     */
+    #[payable]
+    pub fn split_donate(&mut self, split_id: String, memo: Option<String>) {
+        let donor: AccountId = Self::_msg_sender();
+        let amount: Balance = env::attached_deposit();
+        require!(amount > 0, "PaymentSplitter: donation is 0");
+
+        self._receive_donation(
+            Donation {
+                donor: donor.clone(),
+                token: None,
+                payee_id: None,
+                split_id: Some(split_id.clone()),
+                amount,
+                timestamp: env::block_timestamp(),
+                memo: memo.clone(),
+            },
+            None,
+        );
+
+        events::emit(
+            "payment_received",
+            json!({
+                "from": donor,
+                "amount": amount.to_string(),
+                "memo": memo,
+                "split_id": split_id,
+            }),
+        );
+    }
+
+    /**
+     * @dev Releases the Ether `account` is owed by `split_id`.
+     */
+    pub fn split_release(&mut self, split_id: String, account: AccountId) {
+        self._split_release(split_id, None, account);
+    }
+
+    /**
+     * @dev Releases the `token` tokens `account` is owed by `split_id`.
+     */
+    pub fn split_release_erc(&mut self, split_id: String, token: AccountId, account: AccountId) {
+        self._split_release(split_id, Some(token), account);
+    }
+
+    /**
+     * @dev Resolves a split release, restoring the payment if the transfer failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_split_release_complete(
+        &mut self,
+        split_id: String,
+        token: Option<AccountId>,
+        account: AccountId,
+        payment: Balance,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!(
+                    "Release of {} from split {} to {} failed, payment restored",
+                    payment,
+                    split_id,
+                    account
+                );
+                let mut split: Split = self._splits.get(&split_id).unwrap();
+                let key = (token.clone(), account);
+                let released_x: Balance = split.released.get(&key).unwrap() - payment;
+                split.released.insert(&key, &released_x);
+                let total_x: Balance = split.total_released.get(&token).unwrap() - payment;
+                split.total_released.insert(&token, &total_x);
+                self._splits.insert(&split_id, &split);
+                self._split_hold(&token, payment, true);
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for up to `limit` split IDs, starting at `from_index`.
+     */
+    pub fn splits(&self, from_index: u64, limit: u64) -> Vec<String> {
+        self._splits
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    /**
+     * @dev Getter for the admins, total shares and payees with their shares of `split_id`.
+     */
+    pub fn split_info(
+        &self,
+        split_id: String,
+    ) -> (Vec<AccountId>, Balance, Vec<(AccountId, Balance)>) {
+        let split: Split = self._split(&split_id);
+        (
+            split.admins.to_vec(),
+            split.total_shares,
+            split
+                .payees
+                .iter()
+                .map(|account| {
+                    let shares_: Balance = split.shares.get(&account).unwrap();
+                    (account, shares_)
+                })
+                .collect(),
+        )
+    }
+
+    /**
+     * @dev Getter for the total amount of Ether (`token` is `None`) or `token` received and
+     * released by `split_id`.
+     */
+    pub fn split_totals(&self, split_id: String, token: Option<AccountId>) -> (Balance, Balance) {
+        let split: Split = self._split(&split_id);
+        (
+            split.received.get(&token).unwrap_or(0),
+            split.total_released.get(&token).unwrap_or(0),
+        )
+    }
+
+    /**
+     * @dev Getter for the amount of Ether (`token` is `None`) or `token` `account` may release
+     * from `split_id`.
+     */
+    pub fn split_releasable(
+        &self,
+        split_id: String,
+        token: Option<AccountId>,
+        account: AccountId,
+    ) -> Balance {
+        self._split(&split_id).releasable(&token, &account)
+    }
+
+    /**
+     * @dev Getter for the Ether (`token` is `None`) or `token` amount held for all splits,
+     * which is excluded from the default split.
+     */
+    pub fn splits_held(&self, token: Option<AccountId>) -> Balance {
+        match token {
+            None => self._splits_held,
+            Some(token) => self._splits_erc20_held.get(&token).unwrap_or(0),
+        }
+    }
+}
+
+impl PaymentSplitter {
+    fn _split(&self, split_id: &String) -> Split {
+        self._splits
+            .get(split_id)
+            .expect("PaymentSplitter: unknown split")
+    }
+
+    /**
+     * @dev Throws if the sender is not an admin of `split_id`, returns the split otherwise.
+     */
+    fn _only_split_admin(&self, split_id: &String) -> Split {
+        let split: Split = self._split(split_id);
+        require!(
+            split.admins.contains(&Self::_msg_sender()),
+            "PaymentSplitter: caller is not a split admin"
+        );
+        split
+    }
+
+    /**
+     * @dev Charges the storage added since `initial_storage` to the attached deposit and refunds
+     * the rest of it to the caller, so split admins cannot grow storage at the expense of the
+     * payees.
+     */
+    fn _charge_split_storage(&self, initial_storage: u64) {
+        let storage_cost: Balance = env::storage_usage().saturating_sub(initial_storage) as Balance
+            * env::storage_byte_cost();
+        let deposit: Balance = env::attached_deposit();
+        require!(
+            deposit >= storage_cost,
+            "PaymentSplitter: attached deposit does not cover the storage"
+        );
+        if deposit > storage_cost {
+            Promise::new(Self::_msg_sender()).transfer(deposit - storage_cost);
+        }
+    }
+
+    /**
+     * @dev Books `amount` of `asset` as received by `split_id`.
+     */
+    pub(crate) fn _split_receive(&mut self, split_id: &String, asset: &Asset, amount: Balance) {
+        let mut split: Split = self._split(split_id);
+        require!(
+            split.total_shares > 0,
+            "PaymentSplitter: split has no payees"
+        );
+        let received_x: Balance = amount + split.received.get(asset).unwrap_or(0);
+        split.received.insert(asset, &received_x);
+        self._splits.insert(split_id, &split);
+        self._split_hold(asset, amount, true);
+    }
+
+    /**
+     * @dev Takes `amount` of `asset` received by `split_id` back out of it, used to pay the
+     * storage of a donation out of the donation.
+     */
+    pub(crate) fn _split_take_back(&mut self, split_id: &String, asset: &Asset, amount: Balance) {
+        let mut split: Split = self._split(split_id);
+        let received_x: Balance = split.received.get(asset).unwrap_or(0) - amount;
+        split.received.insert(asset, &received_x);
+        self._splits.insert(split_id, &split);
+        self._split_hold(asset, amount, false);
+    }
+
+    /**
+     * @dev Adjusts the amount of `asset` held for splits.
+     */
+    fn _split_hold(&mut self, asset: &Asset, amount: Balance, add: bool) {
+        match asset {
+            None => {
+                if add {
+                    self._splits_held += amount;
+                } else {
+                    self._splits_held -= amount;
+                }
+            }
+            Some(token) => {
+                let held: Balance = self._splits_erc20_held.get(token).unwrap_or(0);
+                let held_x: Balance = if add { held + amount } else { held - amount };
+                self._splits_erc20_held.insert(token, &held_x);
+            }
+        }
+    }
+
+    fn _split_release(&mut self, split_id: String, token: Asset, account: AccountId) {
+        let mut split: Split = self._split(&split_id);
+        let payment: Balance = split.releasable(&token, &account);
+        require!(payment != 0, "PaymentSplitter: account is not due payment");
+        if token.is_none() {
+            require!(
+                self._liquid() >= payment,
+                "PaymentSplitter: not enough liquid Ether, unstake first"
+            );
+        }
+
+        let key = (token.clone(), account.clone());
+        let released_x: Balance = payment + split.released.get(&key).unwrap_or(0);
+        split.released.insert(&key, &released_x);
+        let total_x: Balance = payment + split.total_released.get(&token).unwrap_or(0);
+        split.total_released.insert(&token, &total_x);
+        self._splits.insert(&split_id, &split);
+        self._split_hold(&token, payment, false);
+
+        let callback = Self::ext(env::current_account_id())
+            .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+            .on_split_release_complete(split_id.clone(), token.clone(), account.clone(), payment);
+        match &token {
+            None => Promise::new(account.clone())
+                .transfer(payment)
+                .then(callback),
+            Some(token) => ext_ft::ext(token.clone())
+                .with_attached_deposit(1)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(account.to_string(), payment.to_string(), None)
+                .then(callback),
+        };
+        events::emit(
+            "split_payment_released",
+            json!({
+                "split_id": split_id,
+                "token": token,
+                "to": account,
+                "amount": payment.to_string(),
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use near_sdk::test_utils::accounts;
+
+    /// A split "crew" administered by {bob}, paying {bob} 1 and {charlie} 3 shares, which
+    /// received a donation of 4 NEAR.
+    fn crew() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.create_split("crew".to_string(), vec![bob()]);
+        set_context(context(bob()).attached_deposit(ONE_NEAR));
+        contract.split_set_payee("crew".to_string(), bob(), 1);
+        contract.split_set_payee("crew".to_string(), charlie(), 3);
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.split_donate("crew".to_string(), None);
+        contract
+    }
+
+    #[test]
+    fn admin_pays_for_payee_storage() {
+        let mut contract: PaymentSplitter = crew();
+        let donated: Balance = contract.total_donated();
+        set_context(context(bob()).attached_deposit(ONE_NEAR));
+        let initial_storage: u64 = env::storage_usage();
+        contract.split_set_payee("crew".to_string(), accounts(3), 4);
+        let storage_cost: Balance =
+            (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+        assert!(storage_cost > 0);
+        assert_eq!(transfers(), vec![(bob(), ONE_NEAR - storage_cost)]);
+        assert_eq!(contract.total_donated(), donated);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: attached deposit does not cover the storage")]
+    fn payee_without_deposit_is_rejected() {
+        let mut contract: PaymentSplitter = crew();
+        set_context(&context(bob()));
+        contract.split_set_payee("crew".to_string(), accounts(3), 4);
+    }
+
+    #[test]
+    fn split_release_pays_by_shares() {
+        let mut contract: PaymentSplitter = crew();
+        let (received, _) = contract.split_totals("crew".to_string(), None);
+        set_context(&context(charlie()));
+        contract.split_release("crew".to_string(), charlie());
+        assert_eq!(transfers(), vec![(charlie(), received * 3 / 4)]);
+        assert_eq!(
+            contract.split_releasable("crew".to_string(), None, charlie()),
+            0
+        );
+        assert_eq!(contract.splits_held(None), received - received * 3 / 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: not enough liquid Ether, unstake first")]
+    fn split_release_needs_liquid_ether() {
+        let mut contract: PaymentSplitter = crew();
+        set_context(context(charlie()).account_balance(ONE_NEAR));
+        contract.split_release("crew".to_string(), charlie());
+    }
+}
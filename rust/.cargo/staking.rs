@@ -0,0 +1,468 @@
+//! Staking of idle Ether.
+//!
+//! With a {StakingConfig} set, anyone can delegate up to `stake_bps` basis points (at most
+//! `MAX_STAKE_BPS`) of the Ether the ledger accounts for to a staking pool with {stake}, as long
+//! as the rest, and at least everything payees, splits and the treasury could release right
+//! now, plus `min_liquid` stays in the contract for releases. {unstake} brings the delegation back to
+//! that target when releases drained the liquid part or the config changed, and {withdraw}
+//! returns unstaked Ether once the pool unlocks it, `NUM_EPOCHS_TO_UNLOCK` epochs later.
+//! {harvest} books the rewards the pool paid as pooled income, so they follow the normal split.
+//! A release larger than the liquid Ether fails until enough is unstaked and withdrawn.
+
+use crate::*;
+
+/// Epochs the staking pool keeps unstaked Ether locked.
+pub const NUM_EPOCHS_TO_UNLOCK: u64 = 4;
+
+/// Largest part of the accounted Ether that can be delegated, in basis points.
+pub const MAX_STAKE_BPS: u32 = 5_000;
+
+const GAS_FOR_STAKING_POOL: Gas = Gas(50_000_000_000_000);
+const GAS_FOR_STAKING_BALANCE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_STAKING_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingConfig {
+    /// Staking pool the Ether is delegated to.
+    pub pool: AccountId,
+    /// Part of the accounted Ether to delegate, in basis points, at most `MAX_STAKE_BPS`.
+    pub stake_bps: u32,
+    /// Ether kept liquid on top of the part that is not delegated.
+    pub min_liquid: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingInfo {
+    /// Delegated to the pool and staked, including harvested rewards.
+    pub staked: Balance,
+    /// Unstaked and not withdrawn from the pool yet.
+    pub unstaking: Balance,
+    /// Epoch from which the unstaked Ether can be withdrawn.
+    pub withdrawable_epoch: u64,
+    /// Amount {stake} would delegate now.
+    pub stakeable: Balance,
+    /// Amount {unstake} would unstake now.
+    pub unstakeable: Balance,
+}
+
+#[ext_contract(ext_staking_pool)]
+#[allow(dead_code)] // only used through the generated `ext_staking_pool` module
+pub trait StakingPool {
+    fn deposit_and_stake(&mut self);
+    fn unstake(&mut self, amount: U128);
+    fn withdraw(&mut self, amount: U128);
+    fn get_account_total_balance(&self, account_id: AccountId) -> U128;
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the staking config, or removes it if `config` is `None`. After lowering
+     * `stake_bps`, {unstake} returns the excess. To stop staking, set `stake_bps` to 0, then
+     * unstake and withdraw everything: the pool can only be changed or removed once nothing is
+     * delegated to it. Can only be called by the current owner.
+     */
+    pub fn set_staking_config(&mut self, config: Option<StakingConfig>) {
+        self._only_owner();
+        if let Some(config) = &config {
+            require!(
+                config.stake_bps <= MAX_STAKE_BPS,
+                "PaymentSplitter: stake_bps exceeds 5000"
+            );
+        }
+        let pool: Option<AccountId> = config.as_ref().map(|config| config.pool.clone());
+        if self._staked + self._unstaking > 0 {
+            require!(
+                self._staking.as_ref().map(|config| config.pool.clone()) == pool,
+                "PaymentSplitter: Ether is still delegated to the current pool"
+            );
+        }
+        events::emit(
+            "staking_config_set",
+            json!({
+                "pool": pool,
+                "stake_bps": config.as_ref().map(|config| config.stake_bps),
+                "min_liquid": config.as_ref().map(|config| config.min_liquid.to_string()),
+            }),
+        );
+        self._staking = config;
+    }
+
+    /**
+     * @dev Getter for the staking config, if any.
+     */
+    pub fn staking_config(&self) -> Option<StakingConfig> {
+        self._staking.clone()
+    }
+
+    /**
+     * @dev Getter for the state of the delegation.
+     */
+    pub fn staking_info(&self) -> StakingInfo {
+        StakingInfo {
+            staked: self._staked,
+            unstaking: self._unstaking,
+            withdrawable_epoch: self._unstaked_epoch + NUM_EPOCHS_TO_UNLOCK,
+            stakeable: self._stakeable(),
+            unstakeable: self._unstakeable(),
+        }
+    }
+
+    /**
+     * @dev Delegates idle Ether to the staking pool, up to the configured target. Campaign
+     * contributions are never staked, as they may have to be refunded.
+     */
+    pub fn stake(&mut self) {
+        let pool: AccountId = self._staking_pool();
+        require!(
+            self._campaign.is_none(),
+            "PaymentSplitter: cannot stake during a campaign"
+        );
+        let amount: Balance = self._stakeable();
+        require!(amount > 0, "PaymentSplitter: nothing to stake");
+        self._staked += amount;
+
+        ext_staking_pool::ext(pool.clone())
+            .with_attached_deposit(amount)
+            .with_static_gas(GAS_FOR_STAKING_POOL)
+            .deposit_and_stake()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_staked(amount),
+            );
+        events::emit(
+            "staked",
+            json!({
+                "pool": pool,
+                "amount": amount.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves a delegation, booking the amount as liquid again if it failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_staked(&mut self, amount: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Staking {} failed", amount);
+                self._staked -= amount;
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Unstakes the Ether delegated above the target, or needed to bring the liquid
+     * Ether back to what the config keeps liquid. Unstaking again restarts the unlock period
+     * of everything unstaked.
+     */
+    pub fn unstake(&mut self) {
+        let pool: AccountId = self._staking_pool();
+        let amount: Balance = self._unstakeable();
+        require!(amount > 0, "PaymentSplitter: nothing to unstake");
+        let previous_epoch: u64 = self._unstaked_epoch;
+        self._staked -= amount;
+        self._unstaking += amount;
+        self._unstaked_epoch = env::epoch_height();
+
+        ext_staking_pool::ext(pool.clone())
+            .with_static_gas(GAS_FOR_STAKING_POOL)
+            .unstake(U128(amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_unstaked(amount, previous_epoch),
+            );
+        events::emit(
+            "unstaked",
+            json!({
+                "pool": pool,
+                "amount": amount.to_string(),
+                "withdrawable_epoch": (self._unstaked_epoch + NUM_EPOCHS_TO_UNLOCK).to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves an unstake, booking the amount as staked again if it failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_unstaked(&mut self, amount: Balance, previous_epoch: u64) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Unstaking {} failed", amount);
+                self._unstaking -= amount;
+                self._staked += amount;
+                if self._unstaking == 0 {
+                    self._unstaked_epoch = previous_epoch;
+                }
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Withdraws the unstaked Ether from the staking pool.
+     * Throws before the unlock epoch reported by {staking_info}.
+     */
+    pub fn withdraw(&mut self) {
+        let pool: AccountId = self._staking_pool();
+        let amount: Balance = self._unstaking;
+        require!(amount > 0, "PaymentSplitter: nothing to withdraw");
+        require!(
+            env::epoch_height() >= self._unstaked_epoch + NUM_EPOCHS_TO_UNLOCK,
+            "PaymentSplitter: unstaked Ether is still locked"
+        );
+
+        ext_staking_pool::ext(pool.clone())
+            .with_static_gas(GAS_FOR_STAKING_POOL)
+            .withdraw(U128(amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_withdrawn(amount),
+            );
+        events::emit(
+            "withdrawn",
+            json!({
+                "pool": pool,
+                "amount": amount.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Resolves a withdrawal. The amount is only booked as liquid once it is back, so
+     * {harvest} never mistakes Ether still at the pool for rewards.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_withdrawn(&mut self, amount: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                self._unstaking -= amount;
+                true
+            }
+            _ => {
+                log!("Withdrawing {} failed", amount);
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Fetches the splitter's balance at the staking pool and books what it holds on top of
+     * the delegated Ether as staking rewards, see {on_staking_balance}.
+     */
+    pub fn harvest(&mut self) -> Promise {
+        ext_staking_pool::ext(self._staking_pool())
+            .with_static_gas(GAS_FOR_STAKING_BALANCE)
+            .get_account_total_balance(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_staking_balance(),
+            )
+    }
+
+    /**
+     * @dev Books the rewards found by {harvest} as pooled Ether income, split by shares like a
+     * donation, and adds them to the staked amount. Returns the rewards booked.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_staking_balance(&mut self) -> U128 {
+        let balance: Balance = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                match near_sdk::serde_json::from_slice::<U128>(&value) {
+                    Ok(balance) => balance.0,
+                    Err(_) => {
+                        log!("Invalid get_account_total_balance result");
+                        return U128(0);
+                    }
+                }
+            }
+            _ => {
+                log!("There was an error contacting the staking pool");
+                return U128(0);
+            }
+        };
+        // the pool rounds stake shares down, so the balance can be a few yoctoNEAR short
+        let rewards: Balance = balance.saturating_sub(self._staked + self._unstaking);
+        if rewards > 0 {
+            self._staked += rewards;
+            self._book_donated(&None, rewards);
+            events::emit(
+                "staking_rewards",
+                json!({
+                    "amount": rewards.to_string(),
+                    "staked": self._staked.to_string(),
+                }),
+            );
+        }
+        U128(rewards)
+    }
+}
+
+impl PaymentSplitter {
+    fn _staking_pool(&self) -> AccountId {
+        self._staking
+            .as_ref()
+            .map(|config| config.pool.clone())
+            .expect("PaymentSplitter: staking is not configured")
+    }
+
+    /**
+     * @dev Delegation target and the Ether to keep liquid: the accounted Ether not in the
+     * target, or the Ether that can be released right now if that is more, plus `min_liquid`.
+     */
+    fn _staking_targets(&self) -> (Balance, Balance) {
+        let config: StakingConfig = match &self._staking {
+            Some(config) => config.clone(),
+            None => return (0, 0),
+        };
+        let accounted: Balance = self._accounted(&None);
+        let target: Balance = math::mul_div(
+            accounted,
+            config.stake_bps as Balance,
+            basis_points::TOTAL_BASIS_POINTS,
+        );
+        let keep: Balance = std::cmp::max(accounted - target, self._releasable_now());
+        (target, keep + config.min_liquid)
+    }
+
+    /**
+     * @dev Ether that can be released right now: the releasable amounts of all payees, which
+     * include their credits, plus the Ether held for splits and the fees held for the treasury.
+     */
+    fn _releasable_now(&self) -> Balance {
+        let payees: Balance = (1..=self._payee_count)
+            .map(|i| self.releasable(&self._payee_by_id(&i)))
+            .sum();
+        payees + self._splits_held + self._fees_taken(&None) - self._fees_released(&None)
+    }
+
+    /**
+     * @dev Liquid Ether of the contract, i.e. its balance minus the storage stake.
+     */
+    pub(crate) fn _liquid(&self) -> Balance {
+        env::account_balance()
+            .saturating_sub(env::storage_usage() as Balance * env::storage_byte_cost())
+    }
+
+    fn _stakeable(&self) -> Balance {
+        let (target, keep) = self._staking_targets();
+        std::cmp::min(
+            target.saturating_sub(self._staked + self._unstaking),
+            self._liquid().saturating_sub(keep),
+        )
+    }
+
+    fn _unstakeable(&self) -> Balance {
+        let (target, keep) = self._staking_targets();
+        let missing: Balance = keep.saturating_sub(self._liquid() + self._unstaking);
+        std::cmp::min(
+            self._staked,
+            std::cmp::max(self._staked.saturating_sub(target), missing),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn pool() -> AccountId {
+        "pool.near".parse().unwrap()
+    }
+
+    /// A splitter staking half its Ether with {pool} that received a 4 NEAR donation.
+    fn staking_splitter() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_staking_config(Some(StakingConfig {
+            pool: pool(),
+            stake_bps: MAX_STAKE_BPS,
+            min_liquid: 0,
+        }));
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        contract
+    }
+
+    fn releasable_total(contract: &PaymentSplitter) -> Balance {
+        contract.releasable(&bob()) + contract.releasable(&charlie())
+    }
+
+    #[test]
+    fn stake_delegates_the_target() {
+        let mut contract: PaymentSplitter = staking_splitter();
+        set_context(&context(owner()));
+        let target: Balance = contract._accounted(&None) / 2;
+        assert_eq!(contract.staking_info().stakeable, target);
+        contract.stake();
+        let stake: Call = call("deposit_and_stake");
+        assert_eq!(stake.receiver, pool());
+        assert_eq!(stake.deposit, target);
+        assert_eq!(contract.staking_info().staked, target);
+    }
+
+    #[test]
+    fn releasable_ether_stays_liquid() {
+        let contract: PaymentSplitter = staking_splitter();
+        set_context(
+            context(owner())
+                .storage_usage(0)
+                .account_balance(5 * ONE_NEAR),
+        );
+        // half of the accounted Ether is in the target, but all of it can be released now
+        let releasable: Balance = releasable_total(&contract);
+        assert!(contract._liquid() - releasable < contract._accounted(&None) / 2);
+        assert_eq!(
+            contract.staking_info().stakeable,
+            contract._liquid() - releasable
+        );
+    }
+
+    #[test]
+    fn unstake_brings_back_the_releasable_ether() {
+        let mut contract: PaymentSplitter = staking_splitter();
+        set_context(&context(owner()));
+        contract.stake();
+        set_context(
+            context(owner())
+                .storage_usage(0)
+                .account_balance(3 * ONE_NEAR),
+        );
+        let missing: Balance = releasable_total(&contract) - contract._liquid();
+        assert_eq!(contract.staking_info().unstakeable, missing);
+        contract.unstake();
+        assert_eq!(call("unstake").args["amount"], missing.to_string());
+        assert_eq!(contract.staking_info().unstaking, missing);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: not enough liquid Ether, unstake first")]
+    fn release_beyond_the_liquid_ether_fails() {
+        let mut contract: PaymentSplitter = staking_splitter();
+        let payment: Balance = contract.releasable(&charlie());
+        set_context(context(charlie()).account_balance(payment + 1));
+        contract.release(charlie());
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: stake_bps exceeds 5000")]
+    fn stake_bps_above_the_maximum_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_staking_config(Some(StakingConfig {
+            pool: pool(),
+            stake_bps: MAX_STAKE_BPS + 1,
+            min_liquid: 0,
+        }));
+    }
+}
@@ -0,0 +1,436 @@
+//! Token payouts swapped to a payee's preferred token.
+//!
+//! A payee can ask to be paid in a preferred token, e.g. a stablecoin. Token releases to that
+//! payee are then quoted on the configured DEX, which follows the Ref-Finance interface
+//! (`get_return` and an `ft_transfer_call` carrying swap actions). The splitter then sends the
+//! tokens to the DEX with a `min_amount_out` derived from the quote and the configured slippage
+//! limit, and never below the minimum rate the owner set for the pool, and the DEX sends the
+//! output straight to the payee. A release for which no pool is registered, or quoted below
+//! the minimum rate, is paid in the donated token, or restored if the swap fails.
+//!
+//! Only token releases are swapped: Ether releases are always paid in Ether, or in wNEAR for
+//! payees who asked for it (see `wnear.rs`). A payee who wants Ether income in another token
+//! has to swap the wNEAR themselves.
+
+use crate::*;
+
+const GAS_FOR_GET_RETURN: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_SWAP_QUOTE_CALLBACK: Gas = Gas(100_000_000_000_000);
+const GAS_FOR_SWAP: Gas = Gas(60_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapConfig {
+    /// DEX contract implementing the Ref-Finance swap interface.
+    pub dex: AccountId,
+    /// Largest accepted shortfall of the swap output against the quote, in basis points.
+    pub max_slippage_bps: u32,
+}
+
+/// DEX pool registered for a token pair, with the lowest rate at which the splitter swaps:
+/// at least `min_rate_numerator` of the output token for every `min_rate_denominator` of the
+/// input token, in their smallest units.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapPool {
+    pub pool_id: u64,
+    pub min_rate_numerator: Balance,
+    pub min_rate_denominator: Balance,
+}
+
+/// One swap action of a Ref-Finance `ft_transfer_call` message.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+/// `msg` of the `ft_transfer_call` to the DEX.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapMsg {
+    pub actions: Vec<SwapAction>,
+    /// Receives the output instead of the sender.
+    pub swap_out_recipient: Option<AccountId>,
+}
+
+#[ext_contract(ext_dex)]
+#[allow(dead_code)] // only used through the generated `ext_dex` module
+pub trait Dex {
+    fn get_return(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the DEX used to swap payouts, or disables swaps if `config` is `None`.
+     * Can only be called by the current owner.
+     */
+    pub fn set_swap_config(&mut self, config: Option<SwapConfig>) {
+        self._only_owner();
+        if let Some(config) = &config {
+            require!(
+                (config.max_slippage_bps as Balance) < basis_points::TOTAL_BASIS_POINTS,
+                "PaymentSplitter: slippage limit is too high"
+            );
+        }
+        self._swap_config = config;
+    }
+
+    /**
+     * @dev Getter for the swap configuration, if any.
+     */
+    pub fn swap_config(&self) -> Option<SwapConfig> {
+        self._swap_config.clone()
+    }
+
+    /**
+     * @dev Registers the DEX pool used to swap `token_in` to `token_out` with its minimum rate,
+     * or removes it if `pool` is `None`. Can only be called by the current owner.
+     */
+    pub fn set_swap_pool(
+        &mut self,
+        token_in: AccountId,
+        token_out: AccountId,
+        pool: Option<SwapPool>,
+    ) {
+        self._only_owner();
+        require!(
+            token_in != token_out,
+            "PaymentSplitter: tokens are the same"
+        );
+        let key = (token_in, token_out);
+        match pool {
+            Some(pool) => {
+                require!(
+                    pool.min_rate_numerator > 0 && pool.min_rate_denominator > 0,
+                    "PaymentSplitter: invalid minimum rate"
+                );
+                self._swap_pools.insert(&key, &pool);
+            }
+            None => {
+                self._swap_pools.remove(&key);
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the DEX pool used to swap `token_in` to `token_out`, if any.
+     */
+    pub fn swap_pool(&self, token_in: AccountId, token_out: AccountId) -> Option<SwapPool> {
+        self._swap_pools.get(&(token_in, token_out))
+    }
+
+    /**
+     * @dev Sets the token the caller wants token releases to be paid in, or removes the
+     * preference if `token` is `None`. The caller must be a payee, and has to be registered
+     * with `token` to receive it. Ether releases are not swapped.
+     */
+    pub fn set_payout_token(&mut self, token: Option<AccountId>) {
+        let account: AccountId = Self::_msg_sender();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        match &token {
+            Some(token) => {
+                self._payout_token.insert(&account, token);
+            }
+            None => {
+                self._payout_token.remove(&account);
+            }
+        }
+        events::emit(
+            "payout_token_set",
+            json!({
+                "account": account,
+                "token": token,
+            }),
+        );
+    }
+
+    /**
+     * @dev Getter for the token `account` wants token releases to be paid in, if any.
+     */
+    pub fn payout_token(&self, account: AccountId) -> Option<AccountId> {
+        self._payout_token.get(&account)
+    }
+
+    /**
+     * @dev Swaps a release of `payment` of `token` to `token_out` with the minimum output
+     * derived from the quote and the minimum rate of the pool, sending the output to `account`.
+     * Resolves in {on_release_erc_complete}, which restores the payment if the swap did not go
+     * through. A missing quote or one below the minimum rate restores the payment right away.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_swap_quote(
+        &mut self,
+        token: AccountId,
+        account: AccountId,
+        payment: Balance,
+        token_out: AccountId,
+        pool: SwapPool,
+    ) {
+        let quote: Option<U128> = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice(&value).ok(),
+            _ => None,
+        };
+        let floor: Balance =
+            math::mul_div(payment, pool.min_rate_numerator, pool.min_rate_denominator);
+        let (config, quote) = match (self._swap_config.clone(), quote) {
+            (Some(config), Some(quote)) if quote.0 > 0 && quote.0 >= floor => (config, quote.0),
+            _ => {
+                log!(
+                    "No quote at the minimum rate for {} {} to {}, payment restored",
+                    payment,
+                    token,
+                    token_out
+                );
+                self._sub_erc_released(&token, &account, payment);
+                return;
+            }
+        };
+        let min_amount_out: Balance = std::cmp::max(
+            math::mul_div(
+                quote,
+                basis_points::TOTAL_BASIS_POINTS - config.max_slippage_bps as Balance,
+                basis_points::TOTAL_BASIS_POINTS,
+            ),
+            floor,
+        );
+        let msg: SwapMsg = SwapMsg {
+            actions: vec![SwapAction {
+                pool_id: pool.pool_id,
+                token_in: token.clone(),
+                token_out: token_out.clone(),
+                amount_in: Some(U128(payment)),
+                min_amount_out: U128(min_amount_out),
+            }],
+            swap_out_recipient: Some(account.clone()),
+        };
+        ext_ft::ext(token.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_SWAP)
+            .ft_transfer_call(
+                config.dex.to_string(),
+                payment.to_string(),
+                None,
+                near_sdk::serde_json::to_string(&msg).unwrap(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                    .on_release_erc_complete(token.clone(), account.clone(), payment),
+            );
+        events::emit(
+            "payment_swapped",
+            json!({
+                "account": account,
+                "token_in": token,
+                "token_out": token_out,
+                "amount_in": payment.to_string(),
+                "min_amount_out": min_amount_out.to_string(),
+            }),
+        );
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev DEX and pool to swap a release of `token` to `account` through, if the payee prefers
+     * another token and a pool for the pair is registered.
+     */
+    pub(crate) fn _swap_route(
+        &self,
+        token: &AccountId,
+        account: &AccountId,
+    ) -> Option<(AccountId, AccountId, SwapPool)> {
+        let config: SwapConfig = self._swap_config.clone()?;
+        let token_out: AccountId = self._payout_token.get(account)?;
+        if token_out == *token {
+            return None;
+        }
+        let pool: SwapPool = self._swap_pools.get(&(token.clone(), token_out.clone()))?;
+        Some((config.dex, token_out, pool))
+    }
+
+    /**
+     * @dev Quotes the swap of `payment` of `token` to `token_out` on `dex` and continues the
+     * release in {on_swap_quote}.
+     */
+    pub(crate) fn _release_erc_swapped(
+        &mut self,
+        token: AccountId,
+        account: AccountId,
+        payment: Balance,
+        (dex, token_out, pool): (AccountId, AccountId, SwapPool),
+    ) {
+        ext_dex::ext(dex)
+            .with_static_gas(GAS_FOR_GET_RETURN)
+            .get_return(
+                pool.pool_id,
+                token.clone(),
+                U128(payment),
+                token_out.clone(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_SWAP_QUOTE_CALLBACK)
+                    .on_swap_quote(token, account, payment, token_out, pool),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn token() -> AccountId {
+        "token.near".parse().unwrap()
+    }
+
+    fn usdc() -> AccountId {
+        "usdc.near".parse().unwrap()
+    }
+
+    fn dex() -> AccountId {
+        "dex.near".parse().unwrap()
+    }
+
+    /// A splitter holding 1000 of {token}, paying {charlie} in {usdc} through pool 7 with a 1%
+    /// slippage limit and a minimum rate of 2 usdc per token.
+    fn swapping_splitter() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_accepted_token(token(), Some(0));
+        contract.set_swap_config(Some(SwapConfig {
+            dex: dex(),
+            max_slippage_bps: 100,
+        }));
+        contract.set_swap_pool(
+            token(),
+            usdc(),
+            Some(SwapPool {
+                pool_id: 7,
+                min_rate_numerator: 2,
+                min_rate_denominator: 1,
+            }),
+        );
+        set_context(&context(token()));
+        contract.ft_on_transfer(owner(), U128(1000), String::new());
+        set_context(&context(charlie()));
+        contract.set_payout_token(Some(usdc()));
+        contract
+    }
+
+    /// Releases {charlie}'s 750 tokens and resolves the quote with `quote`.
+    fn release_quoted(contract: &mut PaymentSplitter, quote: PromiseResult) {
+        contract.release_erc(token(), charlie());
+        set_callback_context(&context(splitter_account()), quote);
+        let pool: SwapPool = contract.swap_pool(token(), usdc()).unwrap();
+        contract.on_swap_quote(token(), charlie(), 750, usdc(), pool);
+    }
+
+    fn swap_msg() -> near_sdk::serde_json::Value {
+        let swap: Call = call("ft_transfer_call");
+        assert_eq!(swap.receiver, token());
+        assert_eq!(swap.deposit, 1);
+        assert_eq!(swap.args["receiver_id"], "dex.near");
+        assert_eq!(swap.args["amount"], "750");
+        near_sdk::serde_json::from_str(swap.args["msg"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn release_is_quoted_on_the_dex() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        contract.release_erc(token(), charlie());
+        let quote: Call = call("get_return");
+        assert_eq!(quote.receiver, dex());
+        assert_eq!(quote.args["pool_id"], 7);
+        assert_eq!(quote.args["amount_in"], "750");
+        assert_eq!(quote.args["token_out"], "usdc.near");
+        assert_eq!(contract.released_erc(&token(), &charlie()), 750);
+    }
+
+    #[test]
+    fn release_without_pool_is_paid_in_the_token() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        set_context(&context(owner()));
+        contract.set_swap_pool(token(), usdc(), None);
+        contract.release_erc(token(), charlie());
+        let transfer: Call = call("ft_transfer");
+        assert_eq!(transfer.receiver, token());
+        assert_eq!(transfer.args["receiver_id"], "charlie");
+        assert_eq!(transfer.args["amount"], "750");
+    }
+
+    #[test]
+    fn swap_takes_the_slippage_limit_off_the_quote() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        release_quoted(&mut contract, json_result(&U128(2000)));
+        let msg = swap_msg();
+        assert_eq!(msg["actions"][0]["pool_id"], 7);
+        assert_eq!(msg["actions"][0]["min_amount_out"], "1980");
+        assert_eq!(msg["swap_out_recipient"], "charlie");
+        assert_eq!(contract.released_erc(&token(), &charlie()), 750);
+    }
+
+    #[test]
+    fn minimum_rate_raises_the_minimum_output() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        release_quoted(&mut contract, json_result(&U128(1510)));
+        // 1% below the quote is 1494, the minimum rate asks for 1500
+        assert_eq!(swap_msg()["actions"][0]["min_amount_out"], "1500");
+    }
+
+    #[test]
+    fn quote_below_the_minimum_rate_restores_the_payment() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        release_quoted(&mut contract, json_result(&U128(1499)));
+        assert!(calls().is_empty());
+        assert_eq!(contract.released_erc(&token(), &charlie()), 0);
+        assert_eq!(contract.releasable_erc(token(), &charlie()), 750);
+    }
+
+    #[test]
+    fn failed_quote_restores_the_payment() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        release_quoted(&mut contract, PromiseResult::Failed);
+        assert!(calls().is_empty());
+        assert_eq!(contract.released_erc(&token(), &charlie()), 0);
+    }
+
+    #[test]
+    fn failed_swap_restores_the_payment() {
+        let mut contract: PaymentSplitter = swapping_splitter();
+        release_quoted(&mut contract, json_result(&U128(2000)));
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_erc_complete(token(), charlie(), 750));
+        assert_eq!(contract.released_erc(&token(), &charlie()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: invalid minimum rate")]
+    fn zero_minimum_rate_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_swap_pool(
+            token(),
+            usdc(),
+            Some(SwapPool {
+                pool_id: 7,
+                min_rate_numerator: 0,
+                min_rate_denominator: 1,
+            }),
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Shared setup of the unit tests: a splitter with two payees in a mocked blockchain, and
+//! helpers to drive callbacks and inspect the receipts a call created.
+
+use crate::*;
+use near_sdk::mock::VmAction;
+use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+
+/// A function call created by the contract: receiver, method, JSON arguments and deposit.
+pub struct Call {
+    pub receiver: AccountId,
+    pub method: String,
+    pub args: near_sdk::serde_json::Value,
+    pub deposit: Balance,
+}
+
+pub fn splitter_account() -> AccountId {
+    "splitter.near".parse().unwrap()
+}
+
+pub fn owner() -> AccountId {
+    accounts(0)
+}
+
+/// Payee with 1 share.
+pub fn bob() -> AccountId {
+    accounts(1)
+}
+
+/// Payee with 3 shares.
+pub fn charlie() -> AccountId {
+    accounts(2)
+}
+
+/// Context of a call by `predecessor` with a liquid balance of 100 NEAR.
+pub fn context(predecessor: AccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(splitter_account())
+        .predecessor_account_id(predecessor)
+        .account_balance(100 * ONE_NEAR);
+    builder
+}
+
+/// Switches to `builder`, keeping the contract storage.
+pub fn set_context(builder: &VMContextBuilder) {
+    testing_env!(builder.build());
+}
+
+/// Switches to `builder` as a callback receiving `result`.
+pub fn set_callback_context(builder: &VMContextBuilder, result: PromiseResult) {
+    testing_env!(
+        builder.build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        vec![result],
+    );
+}
+
+/// A `PromiseResult` carrying `value` as JSON.
+pub fn json_result<T: Serialize>(value: &T) -> PromiseResult {
+    PromiseResult::Successful(near_sdk::serde_json::to_vec(value).unwrap())
+}
+
+/// A splitter owned by {owner}, paying {bob} 1 and {charlie} 3 shares.
+pub fn splitter() -> PaymentSplitter {
+    set_context(&context(owner()));
+    let payee = |account: AccountId, shares: Balance| PayeeSpec {
+        account: account.clone(),
+        shares,
+        name: account.to_string(),
+        chain: "near".to_string(),
+        wallet: account,
+        splitter: false,
+    };
+    PaymentSplitter::new(None, Some(vec![payee(bob(), 1), payee(charlie(), 3)]), None)
+}
+
+/// Function calls created by the last call, in order.
+pub fn calls() -> Vec<Call> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver: AccountId = receipt.receiver_id.clone();
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::FunctionCall {
+                        function_name,
+                        args,
+                        deposit,
+                        ..
+                    } => Some(Call {
+                        receiver: receiver.clone(),
+                        method: function_name,
+                        args: near_sdk::serde_json::from_slice(&args)
+                            .unwrap_or(near_sdk::serde_json::Value::Null),
+                        deposit,
+                    }),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// Ether transfers created by the last call, as receiver and amount.
+pub fn transfers() -> Vec<(AccountId, Balance)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver: AccountId = receipt.receiver_id.clone();
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::Transfer { deposit } => Some((receiver.clone(), deposit)),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// The call to `method` created by the last call, panicking if there is none.
+pub fn call(method: &str) -> Call {
+    calls()
+        .into_iter()
+        .find(|call| call.method == method)
+        .unwrap_or_else(|| panic!("no call to {}", method))
+}
@@ -0,0 +1,121 @@
+//! Minimum payout thresholds.
+//!
+//! Batch releases ({release_all} and {release_round}) skip payees whose releasable amount is
+//! below their threshold for the asset, so they are not paid dust. A threshold can be set per
+//! payee or as the default of an asset. An explicit {release} ignores thresholds.
+
+use crate::splits::Asset;
+use crate::*;
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the minimum payout of `account` in Ether (`token` is `None`) or in a NEP-141
+     * `token` for batch releases. With `account` `None` it sets the default of the asset for
+     * payees without a threshold of their own. A `threshold` of `None` removes it.
+     * Can only be called by the current owner.
+     */
+    pub fn set_payout_threshold(
+        &mut self,
+        account: Option<AccountId>,
+        token: Option<AccountId>,
+        threshold: Option<Balance>,
+    ) {
+        self._only_owner();
+        if let Some(account) = &account {
+            require!(
+                self._shares.get(account).unwrap_or(0) > 0,
+                "PaymentSplitter: account has no shares"
+            );
+        }
+        let key = (token, account);
+        match threshold {
+            Some(threshold) => {
+                self._payout_thresholds.insert(&key, &threshold);
+            }
+            None => {
+                self._payout_thresholds.remove(&key);
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the minimum payout of `account` in Ether (`token` is `None`) or in a
+     * NEP-141 `token` for batch releases: its own threshold, else the asset default, else 0.
+     */
+    pub fn payout_threshold(&self, account: AccountId, token: Option<AccountId>) -> Balance {
+        self._payout_thresholds
+            .get(&(token.clone(), Some(account)))
+            .or_else(|| self._payout_thresholds.get(&(token, None)))
+            .unwrap_or(0)
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Whether a batch release should pay `payment` of `asset` to `account`.
+     */
+    pub(crate) fn _meets_threshold(
+        &self,
+        asset: &Asset,
+        account: &AccountId,
+        payment: Balance,
+    ) -> bool {
+        payment > 0 && payment >= self.payout_threshold(account.clone(), asset.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// A splitter with a donation of 4 NEAR, which makes about 1 NEAR due to {bob} and 3 NEAR to
+    /// {charlie}, an Ether default threshold of 2 NEAR and payee overrides of half a NEAR for
+    /// {bob} and 5 NEAR for {charlie}.
+    fn thresholds() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_payout_threshold(None, None, Some(2 * ONE_NEAR));
+        contract.set_payout_threshold(Some(bob()), None, Some(ONE_NEAR / 2));
+        contract.set_payout_threshold(Some(charlie()), None, Some(5 * ONE_NEAR));
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        set_context(&context(owner()));
+        contract
+    }
+
+    #[test]
+    fn payee_override_takes_precedence_over_the_asset_default() {
+        let mut contract: PaymentSplitter = thresholds();
+        assert_eq!(contract.payout_threshold(bob(), None), ONE_NEAR / 2);
+        assert_eq!(contract.payout_threshold(charlie(), None), 5 * ONE_NEAR);
+        let due_bob: Balance = contract.releasable(&bob());
+
+        // bob is below the default but above his own threshold, charlie the other way round
+        assert!(contract.release_all(None));
+        assert_eq!(transfers(), vec![(bob(), due_bob)]);
+        assert!(contract.releasable(&charlie()) > 0);
+    }
+
+    #[test]
+    fn removed_override_falls_back_to_the_asset_default() {
+        let mut contract: PaymentSplitter = thresholds();
+        contract.set_payout_threshold(Some(charlie()), None, None);
+        assert_eq!(contract.payout_threshold(charlie(), None), 2 * ONE_NEAR);
+        let due_charlie: Balance = contract.releasable(&charlie());
+        contract.set_payout_threshold(Some(bob()), None, None);
+        let due_bob: Balance = contract.releasable(&bob());
+        assert!(due_bob < 2 * ONE_NEAR);
+
+        assert!(contract.release_all(None));
+        assert_eq!(transfers(), vec![(charlie(), due_charlie)]);
+    }
+
+    #[test]
+    fn explicit_release_ignores_thresholds() {
+        let mut contract: PaymentSplitter = thresholds();
+        let due_charlie: Balance = contract.releasable(&charlie());
+        contract.release(charlie());
+        assert_eq!(transfers(), vec![(charlie(), due_charlie)]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Linear vesting of payee entitlements.
+//!
+//! A payee with a vesting schedule can only release the vested part of its entitlement:
+//! nothing before the cliff, then a share growing linearly from `start` until `start +
+//! duration`, after which everything accrued is vested. The schedule applies to every asset and
+//! to income received at any time, so income arriving after the end vests immediately.
+
+use crate::splits::Asset;
+use crate::*;
+
+/// Times are block timestamps in nanoseconds, `cliff` and `duration` are relative to `start`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    pub start: u64,
+    pub cliff: u64,
+    pub duration: u64,
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the vesting schedule of `account`, replacing any previous one. The schedule must
+     * vest at least what `account` already released in every asset.
+     * Can only be called by the current owner.
+     */
+    pub fn set_vesting_schedule(&mut self, account: AccountId, schedule: VestingSchedule) {
+        self._only_owner();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        require!(schedule.duration > 0, "PaymentSplitter: duration is 0");
+        require!(
+            schedule.cliff <= schedule.duration,
+            "PaymentSplitter: cliff is longer than duration"
+        );
+        self._vesting.insert(&account, &schedule);
+        for asset in self._pool_assets() {
+            let released: Balance = match &asset {
+                None => self.released(&account),
+                Some(token) => self.released_erc(token, &account),
+            };
+            require!(
+                self._vested_amount(&account, self._entitlement(&asset, &account)) >= released,
+                "PaymentSplitter: schedule vests less than already released"
+            );
+        }
+        events::emit(
+            "vesting_schedule_set",
+            json!({
+                "account": account,
+                "start": schedule.start.to_string(),
+                "cliff": schedule.cliff.to_string(),
+                "duration": schedule.duration.to_string(),
+            }),
+        );
+    }
+
+    /**
+     * @dev Removes the vesting schedule of `account`, vesting everything accrued.
+     * Can only be called by the current owner.
+     */
+    pub fn remove_vesting_schedule(&mut self, account: AccountId) {
+        self._only_owner();
+        require!(
+            self._vesting.remove(&account).is_some(),
+            "PaymentSplitter: account has no vesting schedule"
+        );
+    }
+
+    /**
+     * @dev Getter for the vesting schedule of `account`, if any.
+     */
+    pub fn vesting_schedule(&self, account: AccountId) -> Option<VestingSchedule> {
+        self._vesting.get(&account)
+    }
+
+    /**
+     * @dev Getter for the vested part of the entitlement of `account` in Ether (`token` is
+     * `None`) or in a NEP-141 `token`, including what was already released.
+     */
+    pub fn vested(&self, account: AccountId, token: Option<AccountId>) -> Balance {
+        self._vested_amount(&account, self._entitlement(&token, &account))
+    }
+
+    /**
+     * @dev Getter for the part of the entitlement of `account` in Ether (`token` is `None`) or
+     * in a NEP-141 `token` that has not vested yet.
+     */
+    pub fn unvested(&self, account: AccountId, token: Option<AccountId>) -> Balance {
+        let entitlement: Balance = self._entitlement(&token, &account);
+        entitlement - self._vested_amount(&account, entitlement)
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Part of `entitlement` vested for `account` at the current block timestamp.
+     */
+    pub(crate) fn _vested_amount(&self, account: &AccountId, entitlement: Balance) -> Balance {
+        let schedule: VestingSchedule = match self._vesting.get(account) {
+            Some(schedule) => schedule,
+            None => return entitlement,
+        };
+        let elapsed: u64 = env::block_timestamp().saturating_sub(schedule.start);
+        if elapsed < schedule.cliff {
+            0
+        } else if elapsed >= schedule.duration {
+            entitlement
+        } else {
+            math::mul_div(
+                entitlement,
+                elapsed as Balance,
+                schedule.duration as Balance,
+            )
+        }
+    }
+
+    /**
+     * @dev Everything `account` accrued in `asset`: its pooled entitlement plus credits and
+     * gifts, before vesting and releases.
+     */
+    fn _entitlement(&self, asset: &Asset, account: &AccountId) -> Balance {
+        if self._shares.get(account).unwrap_or(0) == 0 {
+            return 0;
+        }
+        let pooled: Balance = self._pooled_entitlement(asset, account, self._pool_received(asset));
+        match asset {
+            None => pooled + self.credited(account),
+            Some(token) => pooled + self.credited_erc(token, account),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const DURATION: u64 = 1_000;
+
+    fn schedule(cliff: u64) -> VestingSchedule {
+        VestingSchedule {
+            start: 0,
+            cliff,
+            duration: DURATION,
+        }
+    }
+
+    /// Donates 4 NEAR and returns {bob}'s entitlement from it.
+    fn donate(contract: &mut PaymentSplitter) -> Balance {
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        contract._pool_received(&None) / 4
+    }
+
+    fn at(timestamp: u64) {
+        set_context(context(bob()).block_timestamp(timestamp));
+    }
+
+    #[test]
+    fn entitlement_vests_linearly_after_the_cliff() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_vesting_schedule(bob(), schedule(DURATION / 4));
+        let entitlement: Balance = donate(&mut contract);
+
+        at(DURATION / 4 - 1);
+        assert_eq!(contract.vested(bob(), None), 0);
+        at(DURATION / 2);
+        assert_eq!(contract.vested(bob(), None), entitlement / 2);
+        assert_eq!(
+            contract.unvested(bob(), None),
+            entitlement - entitlement / 2
+        );
+        contract.release(bob());
+        assert_eq!(contract.released(&bob()), entitlement / 2);
+        at(DURATION);
+        assert_eq!(contract.releasable(&bob()), entitlement - entitlement / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: schedule vests less than already released")]
+    fn schedule_vesting_less_than_released_is_rejected() {
+        let mut contract: PaymentSplitter = splitter();
+        donate(&mut contract);
+        at(DURATION / 2);
+        contract.release(bob());
+
+        // the cliff of the new schedule is still ahead, so none of the released amount vested
+        set_context(context(owner()).block_timestamp(DURATION / 2));
+        contract.set_vesting_schedule(bob(), schedule(DURATION * 3 / 4));
+    }
+
+    #[test]
+    fn schedule_vesting_what_was_released_is_accepted() {
+        let mut contract: PaymentSplitter = splitter();
+        let entitlement: Balance = donate(&mut contract);
+        at(DURATION);
+        contract.release(bob());
+
+        set_context(context(owner()).block_timestamp(DURATION));
+        contract.set_vesting_schedule(bob(), schedule(0));
+        assert_eq!(contract.vested(bob(), None), entitlement);
+        assert_eq!(contract.releasable(&bob()), 0);
+    }
+}
@@ -0,0 +1,358 @@
+//! wNEAR handled as Ether.
+//!
+//! With a wrap contract configured, wNEAR received through `ft_on_transfer` is unwrapped and
+//! booked as an Ether donation, so it is split together with native NEAR. Payees can ask for
+//! their Ether releases in wNEAR, which the splitter wraps on the way out. NEAR stuck as wNEAR
+//! after a failed unwrap or a failed wrapped payout is held until {unwrap_held} is called.
+//! A failed wrapped payout is not a failed release: the payee is not flagged for its backup
+//! beneficiary, it can switch back to NEAR payouts and release again.
+//! The splitter has to be registered with the wNEAR contract to wrap NEAR.
+
+use crate::*;
+
+const GAS_FOR_NEAR_WITHDRAW: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_NEAR_DEPOSIT: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_UNWRAP_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WRAP_CALLBACK: Gas = Gas(30_000_000_000_000);
+
+#[ext_contract(ext_wrap)]
+#[allow(dead_code)] // only used through the generated `ext_wrap` module
+pub trait WrapNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[near_bindgen]
+impl PaymentSplitter {
+    /**
+     * @dev Sets the wNEAR contract, or stops treating any token as wNEAR if `wrap` is `None`.
+     * Throws while NEAR is held as wNEAR of the current contract, or is being unwrapped.
+     * Can only be called by the current owner.
+     */
+    pub fn set_wrap_contract(&mut self, wrap: Option<AccountId>) {
+        self._only_owner();
+        require!(
+            self._wrapped_held == 0,
+            "PaymentSplitter: wrapped NEAR is still held"
+        );
+        require!(
+            self._unwrapping == 0,
+            "PaymentSplitter: wrapped NEAR is being unwrapped"
+        );
+        self._wrap_contract = wrap;
+    }
+
+    /**
+     * @dev Getter for the wNEAR contract, if any.
+     */
+    pub fn wrap_contract(&self) -> Option<AccountId> {
+        self._wrap_contract.clone()
+    }
+
+    /**
+     * @dev Sets whether the caller wants Ether releases paid in wNEAR. The caller must be a
+     * payee, and has to be registered with the wNEAR contract to receive it.
+     */
+    pub fn set_wrapped_payout(&mut self, wrapped: bool) {
+        let account: AccountId = Self::_msg_sender();
+        require!(
+            self._shares.get(&account).unwrap_or(0) > 0,
+            "PaymentSplitter: account has no shares"
+        );
+        if wrapped {
+            self._payout_wrapped.insert(&account, &true);
+        } else {
+            self._payout_wrapped.remove(&account);
+        }
+    }
+
+    /**
+     * @dev Getter for whether `account` wants Ether releases paid in wNEAR.
+     */
+    pub fn is_payout_wrapped(&self, account: AccountId) -> bool {
+        self._payout_wrapped.get(&account).unwrap_or(false)
+    }
+
+    /**
+     * @dev Getter for the NEAR the ledger accounts for that is held as wNEAR.
+     */
+    pub fn wrapped_held(&self) -> Balance {
+        self._wrapped_held
+    }
+
+    /**
+     * @dev Getter for the NEAR whose unwrap is in flight.
+     */
+    pub fn unwrapping(&self) -> Balance {
+        self._unwrapping
+    }
+
+    /**
+     * @dev Unwraps the NEAR held as wNEAR.
+     */
+    pub fn unwrap_held(&mut self) {
+        let amount: Balance = self._wrapped_held;
+        require!(amount > 0, "PaymentSplitter: no wrapped NEAR held");
+        self._wrapped_held = 0;
+        self._unwrap(amount);
+    }
+
+    /**
+     * @dev Resolves an unwrap, holding the amount as wNEAR if it failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_unwrapped(&mut self, amount: Balance) -> bool {
+        self._unwrapping -= amount;
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("Unwrapping {} failed, held as wNEAR", amount);
+                self._wrapped_held += amount;
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Sends a release of `payment` wrapped by {_release_wrapped} on `wrap` to `account`, or
+     * restores the payment if wrapping failed and the NEAR came back.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_release_wrapped(
+        &mut self,
+        wrap: AccountId,
+        account: AccountId,
+        payment: Balance,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                ext_ft::ext(wrap)
+                    .with_attached_deposit(1)
+                    .with_static_gas(GAS_FOR_FT_TRANSFER)
+                    .ft_transfer(account.to_string(), payment.to_string(), None)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_RELEASE_CALLBACK)
+                            .on_release_wrapped_complete(account, payment),
+                    );
+                true
+            }
+            _ => {
+                log!(
+                    "Wrapping {} for {} failed, payment restored",
+                    payment,
+                    account
+                );
+                self._unrelease(&account, payment);
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Resolves a wrapped release. If the wNEAR transfer failed the payment is restored and
+     * the wNEAR is held until {unwrap_held}. The payee is not flagged as unreachable, as the
+     * NEAR it is owed is not liquid until the wNEAR is unwrapped.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_release_wrapped_complete(&mut self, account: AccountId, payment: Balance) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!(
+                    "Release of {} wNEAR to {} failed, payment restored",
+                    payment,
+                    account
+                );
+                self._unrelease(&account, payment);
+                self._wrapped_held += payment;
+                false
+            }
+        }
+    }
+}
+
+impl PaymentSplitter {
+    /**
+     * @dev Whether `token` is the configured wNEAR contract.
+     */
+    pub(crate) fn _is_wrapped_near(&self, token: &AccountId) -> bool {
+        self._wrap_contract.as_ref() == Some(token)
+    }
+
+    /**
+     * @dev Unwraps `amount` of wNEAR, resolving in {on_unwrapped}.
+     */
+    pub(crate) fn _unwrap(&mut self, amount: Balance) {
+        self._unwrapping += amount;
+        ext_wrap::ext(self._wrap_contract.clone().unwrap())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_NEAR_WITHDRAW)
+            .near_withdraw(U128(amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_UNWRAP_CALLBACK)
+                    .on_unwrapped(amount),
+            );
+    }
+
+    /**
+     * @dev Whether an Ether release to `account` should be paid in wNEAR.
+     */
+    pub(crate) fn _pays_wrapped(&self, account: &AccountId) -> bool {
+        self._wrap_contract.is_some() && self.is_payout_wrapped(account.clone())
+    }
+
+    /**
+     * @dev Wraps `payment` Ether and continues the release to `account` in
+     * {on_release_wrapped}.
+     */
+    pub(crate) fn _release_wrapped(&mut self, account: AccountId, payment: Balance) {
+        let wrap: AccountId = self._wrap_contract.clone().unwrap();
+        ext_wrap::ext(wrap.clone())
+            .with_attached_deposit(payment)
+            .with_static_gas(GAS_FOR_NEAR_DEPOSIT)
+            .near_deposit()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_WRAP_CALLBACK)
+                    .on_release_wrapped(wrap, account, payment),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn wrap() -> AccountId {
+        "wrap.near".parse().unwrap()
+    }
+
+    /// A splitter using {wrap} that received a 4 NEAR donation, with {charlie} paid in wNEAR.
+    fn wrapping_splitter() -> PaymentSplitter {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_wrap_contract(Some(wrap()));
+        set_context(context(owner()).attached_deposit(4 * ONE_NEAR));
+        contract.donate(None, None);
+        set_context(&context(charlie()));
+        contract.set_wrapped_payout(true);
+        contract
+    }
+
+    /// Releases {charlie}'s Ether and returns the payment that is being wrapped.
+    fn release_wrapped(contract: &mut PaymentSplitter) -> Balance {
+        let payment: Balance = contract.releasable(&charlie());
+        contract.release(charlie());
+        let deposit: Call = call("near_deposit");
+        assert_eq!(deposit.receiver, wrap());
+        assert_eq!(deposit.deposit, payment);
+        payment
+    }
+
+    #[test]
+    fn wrapped_release_is_sent_as_wnear() {
+        let mut contract: PaymentSplitter = wrapping_splitter();
+        let payment: Balance = release_wrapped(&mut contract);
+        set_callback_context(
+            &context(splitter_account()),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.on_release_wrapped(wrap(), charlie(), payment));
+        let transfer: Call = call("ft_transfer");
+        assert_eq!(transfer.receiver, wrap());
+        assert_eq!(transfer.args["receiver_id"], "charlie");
+        assert_eq!(transfer.args["amount"], payment.to_string());
+        assert_eq!(contract.released(&charlie()), payment);
+    }
+
+    #[test]
+    fn failed_wrap_restores_the_payment_without_flagging() {
+        let mut contract: PaymentSplitter = wrapping_splitter();
+        let payment: Balance = release_wrapped(&mut contract);
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_wrapped(wrap(), charlie(), payment));
+        assert_eq!(contract.released(&charlie()), 0);
+        assert_eq!(contract.releasable(&charlie()), payment);
+        assert!(!contract.is_recoverable(&charlie()));
+    }
+
+    #[test]
+    fn failed_wnear_transfer_holds_the_wnear_without_flagging() {
+        let mut contract: PaymentSplitter = wrapping_splitter();
+        let payment: Balance = release_wrapped(&mut contract);
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_wrapped_complete(charlie(), payment));
+        assert_eq!(contract.released(&charlie()), 0);
+        assert_eq!(contract.wrapped_held(), payment);
+        assert!(!contract.is_recoverable(&charlie()));
+    }
+
+    #[test]
+    fn failed_near_release_flags_the_payee() {
+        let mut contract: PaymentSplitter = wrapping_splitter();
+        contract.set_wrapped_payout(false);
+        let payment: Balance = contract.releasable(&charlie());
+        contract.release(charlie());
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_release_complete(charlie(), payment));
+        assert!(contract.is_recoverable(&charlie()));
+    }
+
+    #[test]
+    fn wnear_donation_is_unwrapped() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_wrap_contract(Some(wrap()));
+        set_context(&context(wrap()));
+        contract.ft_on_transfer(owner(), U128(4 * ONE_NEAR), String::new());
+        let withdraw: Call = call("near_withdraw");
+        assert_eq!(withdraw.receiver, wrap());
+        assert_eq!(withdraw.args["amount"], (4 * ONE_NEAR).to_string());
+        assert_eq!(contract.unwrapping(), 4 * ONE_NEAR);
+
+        set_callback_context(
+            &context(splitter_account()),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.on_unwrapped(4 * ONE_NEAR));
+        assert_eq!(contract.unwrapping(), 0);
+        assert_eq!(contract.wrapped_held(), 0);
+        set_context(&context(owner()));
+        contract.set_wrap_contract(None);
+    }
+
+    #[test]
+    fn failed_unwrap_is_held_as_wnear() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_wrap_contract(Some(wrap()));
+        set_context(&context(wrap()));
+        contract.ft_on_transfer(owner(), U128(4 * ONE_NEAR), String::new());
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        assert!(!contract.on_unwrapped(4 * ONE_NEAR));
+        assert_eq!(contract.unwrapping(), 0);
+        assert_eq!(contract.wrapped_held(), 4 * ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: wrapped NEAR is being unwrapped")]
+    fn wrap_contract_cannot_change_during_an_unwrap() {
+        let mut contract: PaymentSplitter = splitter();
+        contract.set_wrap_contract(Some(wrap()));
+        set_context(&context(wrap()));
+        contract.ft_on_transfer(owner(), U128(4 * ONE_NEAR), String::new());
+        set_context(&context(owner()));
+        contract.set_wrap_contract(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "PaymentSplitter: wrapped NEAR is still held")]
+    fn wrap_contract_cannot_change_while_wnear_is_held() {
+        let mut contract: PaymentSplitter = wrapping_splitter();
+        let payment: Balance = release_wrapped(&mut contract);
+        set_callback_context(&context(splitter_account()), PromiseResult::Failed);
+        contract.on_release_wrapped_complete(charlie(), payment);
+        set_context(&context(owner()));
+        contract.set_wrap_contract(None);
+    }
+}
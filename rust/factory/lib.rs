@@ -0,0 +1,358 @@
+//! This contract deploys PaymentSplitter instances as sub-accounts of the factory.
+//!
+//! [store_splitter_code]: stores the PaymentSplitter wasm the factory deploys
+//! [create_splitter]: deploys and initializes a new splitter, paid for by the creator
+//! [splitters_by_creator]: lists the splitters deployed by an account
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LazyOption, UnorderedMap, Vector};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    env, log, near_bindgen, require, AccountId, Balance, Gas, PanicOnDefault, Promise,
+    PromiseResult,
+};
+
+#[path = "../.cargo/payee_spec.rs"]
+mod payee_spec;
+
+pub use payee_spec::PayeeSpec;
+
+const GAS_FOR_NEW: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_NEW_PER_PAYEE: Gas = Gas(4_000_000_000_000);
+const GAS_FOR_CREATE_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// Storage the splitter state needs on top of its code, in bytes
+const EXTRA_STORAGE_BYTES: u128 = 20_000;
+/// Storage the factory's registry entries for a new splitter can take, in bytes. Entries of a
+/// first splitter with creator and splitter IDs of the maximum length take 1015 bytes.
+const REGISTRY_STORAGE_BYTES: u128 = 1_200;
+const MAX_INITIAL_PAYEES: usize = 50;
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct SplitterFactory {
+    _owner: AccountId,
+    _splitter_code: LazyOption<Vec<u8>>,
+    _splitters: Vector<AccountId>,
+    _splitter_creator: UnorderedMap<AccountId, AccountId>,
+    _splitters_by_creator: UnorderedMap<AccountId, Vector<AccountId>>,
+}
+
+#[near_bindgen]
+impl SplitterFactory {
+    /// Contract Initializer
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            _owner: env::predecessor_account_id(),
+            _splitter_code: LazyOption::new(b"a", None),
+            _splitters: Vector::new(b"b"),
+            _splitter_creator: UnorderedMap::new(b"c"),
+            _splitters_by_creator: UnorderedMap::new(b"d"),
+        }
+    }
+
+    /**
+     * @dev Stores the PaymentSplitter wasm passed as raw call input. Replacing it only affects
+     * splitters deployed afterwards. Can only be called by the owner.
+     */
+    pub fn store_splitter_code(&mut self) {
+        require!(
+            env::predecessor_account_id() == self._owner,
+            "Ownable: caller is not the owner"
+        );
+        let code: Vec<u8> = env::input().expect("SplitterFactory: no code attached");
+        self._splitter_code.set(&code);
+        log!("Stored splitter code of {} bytes", code.len());
+    }
+
+    /**
+     * @dev Getter for the deposit `create_splitter` needs to cover the storage of a new splitter
+     * and of its entries in the factory's registry.
+     */
+    pub fn required_deposit(&self) -> Balance {
+        let code_len: u128 = match self._splitter_code.get() {
+            Some(code) => code.len() as u128,
+            None => 0,
+        };
+        (code_len + EXTRA_STORAGE_BYTES + REGISTRY_STORAGE_BYTES) * env::storage_byte_cost()
+    }
+
+    /**
+     * @dev Deploys a PaymentSplitter to `{name}.{factory}` and initializes it with `payees` and
+     * `owner`, all in one batch. The attached deposit pays for the storage of the new account
+     * and of its registry entries, and is refunded to the creator if the deployment fails.
+     * The factory keeps {REGISTRY_STORAGE_BYTES} worth of it until the splitter is registered.
+     */
+    #[payable]
+    pub fn create_splitter(
+        &mut self,
+        name: String,
+        owner: AccountId,
+        payees: Vec<PayeeSpec>,
+    ) -> Promise {
+        let creator: AccountId = env::predecessor_account_id();
+        let deposit: Balance = env::attached_deposit();
+        require!(
+            payees.len() <= MAX_INITIAL_PAYEES,
+            "SplitterFactory: too many initial payees"
+        );
+        require!(
+            deposit >= self.required_deposit(),
+            "SplitterFactory: attached deposit does not cover storage"
+        );
+        let code: Vec<u8> = self
+            ._splitter_code
+            .get()
+            .expect("SplitterFactory: no splitter code stored");
+        let splitter: AccountId = format!("{}.{}", name, env::current_account_id())
+            .parse()
+            .expect("SplitterFactory: invalid splitter name");
+        require!(
+            self._splitter_creator.get(&splitter).is_none(),
+            "SplitterFactory: splitter already exists"
+        );
+
+        let args = json!({
+            "owner": owner,
+            "payees": payees,
+        });
+        let gas_for_new: Gas = GAS_FOR_NEW + GAS_FOR_NEW_PER_PAYEE * payees.len() as u64;
+        let registry_deposit: Balance = REGISTRY_STORAGE_BYTES * env::storage_byte_cost();
+        Promise::new(splitter.clone())
+            .create_account()
+            .transfer(deposit - registry_deposit)
+            .deploy_contract(code)
+            .function_call(
+                "new".to_string(),
+                args.to_string().into_bytes(),
+                0,
+                gas_for_new,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_CREATE_CALLBACK)
+                    .on_splitter_created(splitter, creator, deposit),
+            )
+    }
+
+    /**
+     * @dev Registers a successfully deployed splitter under its creator, paying the registry
+     * storage out of the part of the deposit the factory kept and refunding the rest, or
+     * refunds the creator's whole deposit if any action of the deployment batch failed.
+     */
+    #[private] // Public - but only callable by env::current_account_id()
+    pub fn on_splitter_created(
+        &mut self,
+        splitter: AccountId,
+        creator: AccountId,
+        deposit: Balance,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                let initial_storage: u64 = env::storage_usage();
+                self._splitters.push(&splitter);
+                self._splitter_creator.insert(&splitter, &creator);
+                let mut created: Vector<AccountId> = match self._splitters_by_creator.get(&creator)
+                {
+                    Some(value) => value,
+                    None => Vector::new([b"e".as_slice(), creator.as_bytes()].concat()),
+                };
+                created.push(&splitter);
+                self._splitters_by_creator.insert(&creator, &created);
+                let storage_cost: Balance =
+                    (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+                let registry_deposit: Balance = REGISTRY_STORAGE_BYTES * env::storage_byte_cost();
+                if storage_cost < registry_deposit {
+                    Promise::new(creator.clone()).transfer(registry_deposit - storage_cost);
+                }
+                log!("Splitter {} created by {}", splitter, creator);
+                true
+            }
+            _ => {
+                log!(
+                    "Creating splitter {} failed, refunding {}",
+                    splitter,
+                    creator
+                );
+                Promise::new(creator).transfer(deposit);
+                false
+            }
+        }
+    }
+
+    /**
+     * @dev Getter for the number of splitters deployed by the factory.
+     */
+    pub fn splitter_count(&self) -> u64 {
+        self._splitters.len()
+    }
+
+    /**
+     * @dev Getter for up to `limit` deployed splitters, starting at `from_index`.
+     */
+    pub fn splitters(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        (from_index..std::cmp::min(from_index + limit, self._splitters.len()))
+            .map(|index| self._splitters.get(index).unwrap())
+            .collect()
+    }
+
+    /**
+     * @dev Getter for the account that created `splitter`, if it was deployed by this factory.
+     */
+    pub fn splitter_creator(&self, splitter: AccountId) -> Option<AccountId> {
+        self._splitter_creator.get(&splitter)
+    }
+
+    /**
+     * @dev Getter for up to `limit` splitters deployed by `creator`, starting at `from_index`.
+     */
+    pub fn splitters_by_creator(
+        &self,
+        creator: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<AccountId> {
+        match self._splitters_by_creator.get(&creator) {
+            Some(created) => (from_index..std::cmp::min(from_index + limit, created.len()))
+                .map(|index| created.get(index).unwrap())
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+
+    fn factory_account() -> AccountId {
+        "factory.near".parse().unwrap()
+    }
+
+    /// A creator with an account ID of the maximum length, 64 characters.
+    fn creator() -> AccountId {
+        format!("{}.near", "c".repeat(59)).parse().unwrap()
+    }
+
+    /// A splitter name making the longest possible splitter account ID.
+    fn name() -> String {
+        "s".repeat(64 - ".factory.near".len())
+    }
+
+    fn splitter() -> AccountId {
+        format!("{}.factory.near", name()).parse().unwrap()
+    }
+
+    fn context(predecessor: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(factory_account())
+            .predecessor_account_id(predecessor)
+            .account_balance(100 * ONE_NEAR);
+        builder
+    }
+
+    /// A factory storing 1000 bytes of splitter code.
+    fn factory() -> SplitterFactory {
+        testing_env!(context(factory_account()).build());
+        let mut contract: SplitterFactory = SplitterFactory::new();
+        let mut code_upload = context(factory_account()).build();
+        code_upload.input = vec![0; 1000];
+        testing_env!(code_upload);
+        contract.store_splitter_code();
+        contract
+    }
+
+    fn set_callback_context(result: PromiseResult) {
+        testing_env!(
+            context(factory_account()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    /// Ether transfers created by the last call, as receiver and amount.
+    fn transfers() -> Vec<(AccountId, Balance)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver: AccountId = receipt.receiver_id.clone();
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::Transfer { deposit } => Some((receiver.clone(), deposit)),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    fn registry_deposit() -> Balance {
+        REGISTRY_STORAGE_BYTES * env::storage_byte_cost()
+    }
+
+    #[test]
+    fn factory_keeps_the_registry_deposit() {
+        let mut contract: SplitterFactory = factory();
+        let deposit: Balance = contract.required_deposit();
+        assert_eq!(
+            deposit,
+            (1000 + EXTRA_STORAGE_BYTES + REGISTRY_STORAGE_BYTES) * env::storage_byte_cost()
+        );
+        testing_env!(context(creator()).attached_deposit(deposit).build());
+        contract.create_splitter(name(), creator(), vec![]);
+        assert_eq!(
+            transfers(),
+            vec![(splitter(), deposit - registry_deposit())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "SplitterFactory: attached deposit does not cover storage")]
+    fn deposit_below_the_requirement_is_rejected() {
+        let mut contract: SplitterFactory = factory();
+        let deposit: Balance = contract.required_deposit() - 1;
+        testing_env!(context(creator()).attached_deposit(deposit).build());
+        contract.create_splitter(name(), creator(), vec![]);
+    }
+
+    #[test]
+    fn creator_pays_for_the_registry() {
+        let mut contract: SplitterFactory = factory();
+        set_callback_context(PromiseResult::Successful(vec![]));
+        let initial_storage: u64 = env::storage_usage();
+        assert!(contract.on_splitter_created(splitter(), creator(), 0));
+        let storage_cost: Balance =
+            (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+        assert!(storage_cost > 0);
+        assert_eq!(
+            transfers(),
+            vec![(creator(), registry_deposit() - storage_cost)]
+        );
+        assert_eq!(contract.splitter_count(), 1);
+        assert_eq!(contract.splitter_creator(splitter()), Some(creator()));
+        assert_eq!(
+            contract.splitters_by_creator(creator(), 0, 10),
+            vec![splitter()]
+        );
+    }
+
+    #[test]
+    fn failed_creation_refunds_the_whole_deposit() {
+        let mut contract: SplitterFactory = factory();
+        set_callback_context(PromiseResult::Failed);
+        assert!(!contract.on_splitter_created(splitter(), creator(), 5 * ONE_NEAR));
+        assert_eq!(transfers(), vec![(creator(), 5 * ONE_NEAR)]);
+        assert_eq!(contract.splitter_count(), 0);
+        assert_eq!(contract.splitter_creator(splitter()), None);
+    }
+}
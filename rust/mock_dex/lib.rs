@@ -0,0 +1,256 @@
+//! Mock of the Ref-Finance swap interface the PaymentSplitter uses to pay payees in their
+//! preferred token. Pools swap at a fixed rate out of the mock's own token balances, and an
+//! execution slippage can be set to test the splitter's minimum output.
+//!
+//! [add_pool]: registers a fixed-rate pool and returns its ID
+//! [set_execution_slippage]: makes swaps return less than quoted
+//! [get_return]: quotes a swap like Ref-Finance
+//! [ft_on_transfer]: executes the swap actions of an `ft_transfer_call`
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, require, AccountId, Balance, Gas, PanicOnDefault,
+    PromiseOrValue,
+};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const TOTAL_BASIS_POINTS: Balance = 10_000;
+
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: String, amount: String, memo: Option<String>);
+}
+
+/// Swaps `token_in` to `token_out` at `rate_numerator / rate_denominator`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MockPool {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub rate_numerator: Balance,
+    pub rate_denominator: Balance,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapMsg {
+    pub actions: Vec<SwapAction>,
+    pub swap_out_recipient: Option<AccountId>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockDex {
+    _pools: Vector<MockPool>,
+    _execution_slippage_bps: Balance,
+}
+
+#[near_bindgen]
+impl MockDex {
+    /// Contract Initializer
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            _pools: Vector::new(b"a"),
+            _execution_slippage_bps: 0,
+        }
+    }
+
+    /**
+     * @dev Registers a fixed-rate pool and returns its ID. The mock pays the output out of its
+     * own balance of `token_out`, which has to be funded separately.
+     */
+    pub fn add_pool(&mut self, pool: MockPool) -> u64 {
+        require!(pool.rate_denominator > 0, "MockDex: rate denominator is 0");
+        self._pools.push(&pool);
+        self._pools.len() - 1
+    }
+
+    /**
+     * @dev Makes every swap return `slippage_bps` basis points less than {get_return} quoted.
+     */
+    pub fn set_execution_slippage(&mut self, slippage_bps: Balance) {
+        require!(
+            slippage_bps <= TOTAL_BASIS_POINTS,
+            "MockDex: slippage is too high"
+        );
+        self._execution_slippage_bps = slippage_bps;
+    }
+
+    /**
+     * @dev Quotes the output of swapping `amount_in` of `token_in` in pool `pool_id`.
+     */
+    pub fn get_return(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> U128 {
+        let pool: MockPool = self._pool(pool_id, &token_in, &token_out);
+        U128(amount_in.0 * pool.rate_numerator / pool.rate_denominator)
+    }
+
+    /**
+     * @dev Executes a single swap action and sends the output to `swap_out_recipient`, or to
+     * `sender_id` if it is not set. Panics like Ref-Finance if the output is below
+     * `min_amount_out`, so the token contract refunds the input.
+     */
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let msg: SwapMsg = near_sdk::serde_json::from_str(&msg).expect("MockDex: invalid msg");
+        require!(msg.actions.len() == 1, "MockDex: expected one swap action");
+        let action: &SwapAction = &msg.actions[0];
+        require!(
+            action.token_in == env::predecessor_account_id(),
+            "MockDex: wrong token_in"
+        );
+        require!(
+            action.amount_in.unwrap_or(amount) == amount,
+            "MockDex: amount_in does not match the transfer"
+        );
+        let quote: Balance = self
+            .get_return(
+                action.pool_id,
+                action.token_in.clone(),
+                amount,
+                action.token_out.clone(),
+            )
+            .0;
+        let amount_out: Balance =
+            quote * (TOTAL_BASIS_POINTS - self._execution_slippage_bps) / TOTAL_BASIS_POINTS;
+        require!(amount_out >= action.min_amount_out.0, "E68: slippage error");
+        let recipient: AccountId = msg.swap_out_recipient.unwrap_or(sender_id);
+        ext_ft::ext(action.token_out.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(recipient.to_string(), amount_out.to_string(), None);
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+impl MockDex {
+    fn _pool(&self, pool_id: u64, token_in: &AccountId, token_out: &AccountId) -> MockPool {
+        let pool: MockPool = self._pools.get(pool_id).expect("MockDex: no such pool");
+        require!(
+            pool.token_in == *token_in && pool.token_out == *token_out,
+            "MockDex: pool does not swap these tokens"
+        );
+        pool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn token() -> AccountId {
+        "token.near".parse().unwrap()
+    }
+
+    fn usdc() -> AccountId {
+        "usdc.near".parse().unwrap()
+    }
+
+    /// A dex with pool 0 swapping {token} to {usdc} at 2 usdc per token, called by {token}.
+    fn dex() -> MockDex {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token())
+            .build());
+        let mut dex: MockDex = MockDex::new();
+        dex.add_pool(MockPool {
+            token_in: token(),
+            token_out: usdc(),
+            rate_numerator: 2,
+            rate_denominator: 1,
+        });
+        dex
+    }
+
+    fn swap_msg(pool_id: u64, min_amount_out: Balance) -> String {
+        format!(
+            r#"{{"actions":[{{"pool_id":{},"token_in":"token.near","token_out":"usdc.near","min_amount_out":"{}"}}],"swap_out_recipient":"{}"}}"#,
+            pool_id,
+            min_amount_out,
+            accounts(1)
+        )
+    }
+
+    /// The `ft_transfer` created by the last call, as receiver and JSON args.
+    fn payout() -> (AccountId, near_sdk::serde_json::Value) {
+        get_created_receipts()
+            .into_iter()
+            .find_map(|receipt| match &receipt.actions[..] {
+                [VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                }] if function_name == "ft_transfer" => Some((
+                    receipt.receiver_id.clone(),
+                    near_sdk::serde_json::from_slice(args).unwrap(),
+                )),
+                _ => None,
+            })
+            .expect("no ft_transfer")
+    }
+
+    #[test]
+    fn swap_pays_the_quote_to_the_recipient() {
+        let mut dex: MockDex = dex();
+        assert_eq!(dex.get_return(0, token(), U128(750), usdc()).0, 1500);
+        dex.ft_on_transfer(accounts(0), U128(750), swap_msg(0, 1485));
+        let (receiver, args) = payout();
+        assert_eq!(receiver, usdc());
+        assert_eq!(args["receiver_id"], accounts(1).to_string());
+        assert_eq!(args["amount"], "1500");
+    }
+
+    #[test]
+    fn execution_slippage_is_taken_off_the_output() {
+        let mut dex: MockDex = dex();
+        dex.set_execution_slippage(100);
+        dex.ft_on_transfer(accounts(0), U128(750), swap_msg(0, 1485));
+        assert_eq!(payout().1["amount"], "1485");
+    }
+
+    #[test]
+    #[should_panic(expected = "E68: slippage error")]
+    fn swap_below_the_minimum_output_fails() {
+        let mut dex: MockDex = dex();
+        dex.set_execution_slippage(101);
+        dex.ft_on_transfer(accounts(0), U128(750), swap_msg(0, 1485));
+    }
+
+    #[test]
+    #[should_panic(expected = "MockDex: no such pool")]
+    fn quote_of_a_missing_pool_fails() {
+        dex().get_return(1, token(), U128(750), usdc());
+    }
+
+    #[test]
+    #[should_panic(expected = "MockDex: pool does not swap these tokens")]
+    fn quote_of_other_tokens_fails() {
+        dex().get_return(0, usdc(), U128(750), token());
+    }
+}